@@ -1,35 +1,417 @@
 use maybe_async::maybe_async;
 
-struct SplitStdFs;
+mod cli;
 
-type BufFile = std::io::BufWriter<std::fs::File>;
+const COMPRESS_USAGE: &str = "Usage: ciso [--resume] [--hash] [--sidecar] [--checksums] \
+                              [--split fatx|fat32|none|<bytes>] [--block-aligned] \
+                              [--naming dotted|underscore|numbered] [--output-dir <dir>] <image>";
 
+#[cfg_attr(not(feature = "sync"), tokio::main)]
 #[maybe_async]
-impl ciso::split::SplitFilesystem<std::io::Error, BufFile> for SplitStdFs {
-    async fn create_file(&mut self, name: &std::ffi::OsStr) -> Result<BufFile, std::io::Error> {
-        let file = std::fs::File::create(name)?;
-        let bf: BufFile = std::io::BufWriter::new(file);
-        Ok(bf)
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("verify") => verify(&args[1..]).await,
+        Some("hash") => hash(&args[1..]).await,
+        Some("dat") => dat(&args[1..]).await,
+        Some("fsck") => fsck(&args[1..]).await,
+        Some("repair") => repair(&args[1..]).await,
+        _ => compress(&args).await,
+    }
+}
+
+#[maybe_async]
+async fn verify(args: &[String]) {
+    let mut options = ciso::verify::VerifyOptions::default();
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check-lengths" => options.check_stored_lengths = true,
+            _ => files.push(std::path::PathBuf::from(arg)),
+        }
+    }
+
+    let [original, image] = files.as_slice() else {
+        panic!("Usage: ciso verify [--check-lengths] <original> <image>");
+    };
+
+    let mut original = cli::open_split_image(original).await.unwrap();
+    let image = cli::open_image(image).await.unwrap();
+    let mut image = ciso::read::CSOReader::new(image).await.unwrap();
+
+    let mut progress = cli::ProgressBar::new(image.sector_count() as u64);
+    let report = ciso::verify::verify_image(&mut original, &mut image, &options, |sector| {
+        progress.update(sector as u64 + 1, "");
+    })
+    .await
+    .unwrap();
+    progress.finish("");
+
+    if let Some(original_size) = report.size_mismatch {
+        println!(
+            "Size mismatch: original is {} bytes, image is {} bytes",
+            original_size,
+            image.file_size()
+        );
     }
 
-    async fn close(&mut self, _: BufFile) {}
+    if let Some(first_mismatch) = report.first_mismatch {
+        println!(
+            "{} of {} sectors differ, first at sector {}",
+            report.mismatched_sectors, report.sectors, first_mismatch
+        );
+    }
+
+    if !report.bad_stored_lengths.is_empty() {
+        println!(
+            "{} blocks have a stored length that does not match the index, first at sector {}",
+            report.bad_stored_lengths.len(),
+            report.bad_stored_lengths[0]
+        );
+    }
+
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+
+    println!("{} sectors verified", report.sectors);
+}
+
+fn print_digests(digests: &ciso::hash::Digests) {
+    println!("Size:  {}", digests.size);
+    println!("CRC32: {}", digests.crc32_hex());
+    println!("MD5:   {}", digests.md5_hex());
+    println!("SHA-1: {}", digests.sha1_hex());
 }
 
-#[cfg_attr(not(feature = "sync"), tokio::main)]
 #[maybe_async]
-async fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let file = std::path::PathBuf::from(&args[1]);
-    let output = file.with_extension("cso");
+async fn hash(args: &[String]) {
+    let [image] = args else {
+        panic!("Usage: ciso hash <image>");
+    };
 
-    if file == output {
-        panic!("Input and output cannot be the same!");
+    let image = cli::open_image(std::path::Path::new(image)).await.unwrap();
+    let mut image = ciso::read::CSOReader::new(image).await.unwrap();
+
+    let mut progress = cli::ProgressBar::new(image.sector_count() as u64);
+    let digests = ciso::hash::hash_image(&mut image, |sector| {
+        progress.update(sector as u64 + 1, "");
+    })
+    .await
+    .unwrap();
+    progress.finish("");
+
+    print_digests(&digests);
+}
+
+#[maybe_async]
+async fn dat(args: &[String]) {
+    let [datfile, image] = args else {
+        panic!("Usage: ciso dat <datfile> <image>");
+    };
+
+    let datfile = std::fs::read_to_string(datfile).unwrap();
+    let datfile = ciso::dat::Datafile::parse(&datfile).unwrap();
+
+    let image = cli::open_image(std::path::Path::new(image)).await.unwrap();
+    let mut image = ciso::read::CSOReader::new(image).await.unwrap();
+
+    let mut progress = cli::ProgressBar::new(image.sector_count() as u64);
+    let result = ciso::dat::verify_image(&mut image, &datfile, |sector| {
+        progress.update(sector as u64 + 1, "");
+    })
+    .await
+    .unwrap();
+    progress.finish("");
+
+    match result.matched {
+        Some((game, rom)) => println!("Matched {} ({})", game.name, rom.name),
+        None => {
+            println!("No match found");
+            print_digests(&result.digests);
+            std::process::exit(1);
+        }
     }
+}
 
-    let mut input = std::fs::File::open(file.clone()).unwrap();
-    let mut output = ciso::split::SplitOutput::new(SplitStdFs, file);
+#[maybe_async]
+async fn fsck(args: &[String]) {
+    let mut profile = None;
+    let mut image = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                profile = match args.next().map(String::as_str) {
+                    Some("xbox") => Some(ciso::check::LoaderProfile::Xbox),
+                    Some("psp") => Some(ciso::check::LoaderProfile::Psp),
+                    _ => panic!("Profile should be one of: xbox, psp"),
+                }
+            }
+            _ => image = Some(std::path::PathBuf::from(arg)),
+        }
+    }
 
-    ciso::write::write_ciso_image(&mut input, &mut output, |_| {})
+    let image = image.expect("Usage: ciso fsck [--profile xbox|psp] <image>");
+    let mut image = cli::open_split_image(&image).await.unwrap();
+    let part_sizes = image.part_sizes();
+    let mut parts_valid = true;
+    if let Err(e) = image.validate(None).await {
+        // check_image reports a bad block size or alignment itself
+        let header_issue = matches!(
+            e,
+            ciso::split::SplitValidationError::Image(
+                ciso::layout::Error::InvalidBlockSize(_) | ciso::layout::Error::InvalidAlignment(_)
+            )
+        );
+        if !header_issue {
+            println!("error: {}", e);
+        }
+        parts_valid = false;
+    }
+
+    let sector_count = cli::sector_count(&mut image).await;
+    let mut progress = cli::ProgressBar::new(sector_count);
+    let report = ciso::check::check_image(&mut image, &part_sizes, profile, |sector| {
+        progress.update(sector as u64 + 1, "");
+    })
+    .await;
+    progress.finish("");
+
+    // A short image cannot be checked further, and validation has already
+    // reported why
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            if parts_valid {
+                println!("error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    for issue in report.issues.iter() {
+        let kind = if issue.is_error() { "error" } else { "warning" };
+        println!("{}: {}", kind, issue);
+    }
+
+    if report.has_errors() || !parts_valid {
+        std::process::exit(1);
+    }
+
+    println!("No structural errors found");
+}
+
+#[maybe_async]
+async fn repair(args: &[String]) {
+    let [damaged, output] = args else {
+        panic!("Usage: ciso repair <damaged image> <output>");
+    };
+
+    let naming = ciso::split::SplitNaming::default();
+    let (mut damaged, _) = cli::open_image_parts(std::path::Path::new(damaged), &naming)
         .await
         .unwrap();
+    let output = std::fs::File::create(output).unwrap();
+    let mut output = std::io::BufWriter::new(output);
+
+    let sector_count = cli::sector_count(&mut damaged).await;
+    let mut progress = cli::ProgressBar::new(sector_count);
+    let report = ciso::repair::repair_image(&mut damaged, &mut output, |sector| {
+        progress.update(sector as u64 + 1, "");
+    })
+    .await
+    .unwrap();
+    progress.finish("");
+
+    std::io::Write::flush(&mut output).unwrap();
+    output.get_ref().sync_all().unwrap();
+
+    println!(
+        "Recovered {} sectors, rebuilt {} index entries",
+        report.recovered_sectors, report.rebuilt_entries
+    );
+    for range in report.unrecoverable.iter() {
+        println!(
+            "Unrecoverable sectors {}-{}, replaced with zeros",
+            range.start,
+            range.end - 1
+        );
+    }
+}
+
+#[maybe_async]
+async fn compress(args: &[String]) {
+    let mut resume = false;
+    let mut hash = false;
+    let mut sidecar = false;
+    let mut split_size = ciso::split::SplitSize::default();
+    let mut naming = "dotted";
+    let mut block_aligned = false;
+    let mut output_dir = None;
+    let mut options = ciso::write::WriteOptions::default();
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => resume = true,
+            "--split" => {
+                let arg = args.next().expect("--split needs a size");
+                split_size = cli::parse_split_size(arg);
+            }
+            "--naming" => {
+                naming = args.next().expect("--naming needs a scheme");
+                cli::parse_naming(naming);
+            }
+            "--block-aligned" => block_aligned = true,
+            "--output-dir" => {
+                let arg = args.next().expect("--output-dir needs a directory");
+                output_dir = Some(std::path::PathBuf::from(arg));
+            }
+            "--hash" => hash = true,
+            "--sidecar" => sidecar = true,
+            "--checksums" => options.block_checksums = true,
+            _ => file = Some(std::path::PathBuf::from(arg)),
+        }
+    }
+
+    let file = file.expect(COMPRESS_USAGE);
+    // The sidecar records the digests, which unciso checks after decompressing
+    options.compute_hashes = hash || sidecar;
+    // Dumps split across `game.1.iso`, `game.2.iso` and so on are compressed
+    // as one image named after `game.iso`
+    let mut input = cli::open_split_image(&file).await.unwrap();
+    let file = cli::image_base(&file);
+    let output_dir = output_dir.unwrap_or_else(|| {
+        file.parent()
+            .map(std::path::Path::to_path_buf)
+            .unwrap_or_default()
+    });
+    let output_name = output_dir
+        .join(file.file_name().unwrap())
+        .with_extension("cso");
+
+    if file == output_name {
+        panic!("Input and output cannot be the same!");
+    }
+
+    let mut output = if resume {
+        ciso::split::SplitOutput::reopen(cli::SplitStdFs, file.clone())
+    } else {
+        ciso::split::SplitOutput::new(cli::SplitStdFs, file.clone())
+    };
+    output.set_split_size(split_size);
+    output.set_output_dir(output_dir);
+    output.set_naming(cli::parse_naming(naming));
+    output.set_block_aligned(block_aligned);
+    output.set_progress_callback(|info| {
+        if let ciso::write::ProgressInfo::SplitPartCreated { name, .. } = info {
+            eprint!("\r\x1b[K");
+            eprintln!("Writing {}", name.to_string_lossy());
+        }
+    });
+
+    let mut progress = cli::ProgressBar::new(0);
+    let mut last_block = None;
+    let mut checkpointed = false;
+    let progress_callback = |info| match info {
+        ciso::write::ProgressInfo::SectorCount(count) => progress.set_total(count as u64),
+        ciso::write::ProgressInfo::Resumed(sector) => {
+            eprintln!("Resuming at sector {}", sector);
+        }
+        ciso::write::ProgressInfo::Checkpoint(_) => checkpointed = true,
+        ciso::write::ProgressInfo::SectorFinished(block) => {
+            let status = format!(
+                "{} -> {} ({:.1}%) {}",
+                cli::format_bytes(block.input_bytes),
+                cli::format_bytes(block.output_bytes),
+                block.ratio() * 100.0,
+                cli::format_rate(block.input_bytes, progress.elapsed()),
+            );
+            progress.update(block.sector as u64 + 1, &status);
+            last_block = Some(block);
+        }
+        ciso::write::ProgressInfo::Finished => {
+            let status = match last_block {
+                Some(block) => format!(
+                    "{} -> {} ({:.1}%)",
+                    cli::format_bytes(block.input_bytes),
+                    cli::format_bytes(block.output_bytes),
+                    block.ratio() * 100.0,
+                ),
+                None => String::new(),
+            };
+            progress.finish(&status);
+        }
+        _ => {}
+    };
+
+    let result = if resume {
+        let naming = cli::parse_naming(naming);
+        let mut existing = match cli::open_image_parts(&output_name, &naming).await {
+            Ok((existing, part_sizes)) => {
+                output.set_existing_parts(&part_sizes);
+                existing
+            }
+            Err(ciso::split::SplitOpenError::NotFound) => {
+                let parts: Vec<std::fs::File> = Vec::new();
+                Box::new(ciso::split::SplitFileReader::new(parts).await.unwrap())
+            }
+            Err(e) => panic!("{}", e),
+        };
+        ciso::write::resume_ciso_image(
+            &mut input,
+            &mut existing,
+            &mut output,
+            &options,
+            progress_callback,
+        )
+        .await
+    } else {
+        ciso::write::write_ciso_image_with_options(
+            &mut input,
+            &mut output,
+            &options,
+            progress_callback,
+        )
+        .await
+    };
+
+    let report = match result {
+        Ok(report) => report,
+        // Once a checkpoint is written the parts can be resumed, so they
+        // are kept rather than rolled back. Closing may fail the same way the
+        // write did, but everything up to the checkpoint is already stored.
+        Err(e) if checkpointed => {
+            let _ = output.close().await;
+            panic!(
+                "Compression failed: {}. Pass --resume to continue where it stopped",
+                e
+            );
+        }
+        Err(e) => {
+            output.rollback().await.unwrap();
+            panic!("Compression failed: {}", e);
+        }
+    };
+
+    if let Some(digests) = report.digests.as_ref().filter(|_| hash) {
+        print_digests(digests);
+    }
+
+    if sidecar {
+        let parts = output
+            .parts()
+            .into_iter()
+            .map(|(name, size)| ciso::sidecar::SidecarPart {
+                name: name.to_string_lossy().into_owned(),
+                size,
+            })
+            .collect();
+        let source_name = file.file_name().map(|n| n.to_string_lossy().into_owned());
+        let sidecar = ciso::sidecar::Sidecar::new(source_name, &options, &report, parts);
+        output.write_sidecar(&sidecar).await.unwrap();
+    }
+
+    output.close().await.unwrap();
 }
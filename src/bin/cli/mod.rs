@@ -0,0 +1,333 @@
+// Shared by the binaries, which each use only part of it
+#![allow(dead_code)]
+
+use maybe_async::maybe_async;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Terminal progress bar with throughput and ETA, drawn on stderr
+pub struct ProgressBar {
+    total: u64,
+    done: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            done: 0,
+            started: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+    }
+
+    /// Update the completed amount and redraw if enough time has passed
+    pub fn update(&mut self, done: u64, status: &str) {
+        self.done = done;
+
+        let now = Instant::now();
+        if self
+            .last_draw
+            .is_some_and(|last| now.duration_since(last) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_draw = Some(now);
+        self.draw(status);
+    }
+
+    pub fn finish(&mut self, status: &str) {
+        if self.total != 0 {
+            self.done = self.total;
+        }
+        self.draw(status);
+        eprintln!();
+    }
+
+    fn draw(&self, status: &str) {
+        if self.total == 0 {
+            // Without a total, only the amount done can be shown
+            eprint!("\r\x1b[K{} {}", self.done, status);
+            let _ = std::io::stderr().flush();
+            return;
+        }
+
+        let fraction = self.done as f64 / self.total as f64;
+        let filled = ((fraction * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let eta = if self.done == 0 || fraction >= 1.0 {
+            String::from("--:--")
+        } else {
+            format_duration(elapsed * (1.0 - fraction) / fraction)
+        };
+
+        eprint!(
+            "\r\x1b[K[{}{}] {:5.1}% {} ETA {}",
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            status,
+            eta,
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    pub fn elapsed(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Format a byte count in binary units
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a throughput given bytes processed over elapsed seconds
+pub fn format_rate(bytes: u64, elapsed: f64) -> String {
+    if elapsed <= 0.0 {
+        return String::from("-");
+    }
+
+    format!("{}/s", format_bytes((bytes as f64 / elapsed) as u64))
+}
+
+/// Parse a `--split` argument: a preset name or a size in bytes
+pub fn parse_split_size(arg: &str) -> ciso::split::SplitSize {
+    match arg {
+        "fatx" => ciso::split::SplitSize::Fatx,
+        "fat32" => ciso::split::SplitSize::Fat32,
+        "none" => ciso::split::SplitSize::None,
+        _ => match arg.parse() {
+            Ok(bytes) if bytes > 0 => ciso::split::SplitSize::Bytes(bytes),
+            _ => panic!("Split size should be fatx, fat32, none or a size in bytes above 0"),
+        },
+    }
+}
+
+/// Parse a `--naming` argument
+pub fn parse_naming(arg: &str) -> ciso::split::SplitNaming {
+    match arg {
+        "dotted" => ciso::split::SplitNaming::Dotted,
+        "underscore" => ciso::split::SplitNaming::Underscore,
+        "numbered" => ciso::split::SplitNaming::Numbered,
+        _ => panic!("Naming should be one of: dotted, underscore, numbered"),
+    }
+}
+
+pub type ImageReader = Box<dyn ciso::read::Read<ReadError = std::io::Error>>;
+
+/// Plain name of an image given the name of any of its parts, so that
+/// `game.2.cso` becomes `game.cso`
+///
+/// Files that are only named like parts, such as `Halo_2.iso`, keep their
+/// name, as they do when opened.
+pub fn image_base(file: &std::path::Path) -> std::path::PathBuf {
+    let name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+
+    let entries: Vec<std::ffi::OsString> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.file_name()))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let entries: Vec<&str> = entries.iter().filter_map(|e| e.to_str()).collect();
+
+    match ciso::split::split_image_name(&entries, name) {
+        Some(plain) => file.with_file_name(plain),
+        None => file.to_path_buf(),
+    }
+}
+
+/// Path of the sidecar metadata file for an image or any of its parts
+pub fn sidecar_path(file: &std::path::Path) -> std::path::PathBuf {
+    image_base(file).with_extension("cso.json")
+}
+
+/// Load the sidecar for an image, if there is one
+pub fn load_sidecar(file: &std::path::Path) -> Option<ciso::sidecar::Sidecar> {
+    let json = std::fs::read_to_string(sidecar_path(file)).ok()?;
+    Some(ciso::sidecar::Sidecar::from_json(&json).expect("Invalid sidecar file"))
+}
+
+pub struct StdReadFs;
+
+#[maybe_async]
+impl ciso::split::SplitReadFilesystem<std::io::Error, std::fs::File> for StdReadFs {
+    async fn list_dir(
+        &mut self,
+        dir: &std::path::Path,
+    ) -> Result<Vec<std::ffi::OsString>, std::io::Error> {
+        std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect()
+    }
+
+    async fn open_read(&mut self, path: &std::path::Path) -> Result<std::fs::File, std::io::Error> {
+        std::fs::File::open(path)
+    }
+}
+
+pub type OpenError = ciso::split::SplitOpenError<std::io::Error>;
+
+pub type SplitImage = ciso::split::SplitFileReader<std::io::Error, std::fs::File>;
+
+/// Open the parts of an image given its plain name or the name of any part
+#[maybe_async]
+pub async fn open_split_image(file: &std::path::Path) -> Result<SplitImage, OpenError> {
+    ciso::split::open_split(&mut StdReadFs, file).await
+}
+
+/// Open a compressed image given its plain name or the name of any part,
+/// warning if its parts do not seem to form one image
+#[maybe_async]
+pub async fn open_image(file: &std::path::Path) -> Result<ImageReader, OpenError> {
+    let mut reader = open_split_image(file).await?;
+    validate_parts(&mut reader).await;
+    Ok(Box::from(reader))
+}
+
+/// Like `open_image`, also returning the size of each part, but without
+/// checking that the parts form one image
+///
+/// Parts named with `naming` are looked for first, so that an image written
+/// with any `--naming` can be resumed.
+#[maybe_async]
+pub async fn open_image_parts(
+    file: &std::path::Path,
+    naming: &ciso::split::SplitNaming,
+) -> Result<(ImageReader, Vec<u64>), OpenError> {
+    let reader = ciso::split::open_split_named(&mut StdReadFs, file, naming).await?;
+    let part_sizes = reader.part_sizes();
+    Ok((Box::from(reader), part_sizes))
+}
+
+/// Number of sectors described by the header of a compressed image, or 0
+/// if it cannot be read
+#[maybe_async]
+pub async fn sector_count<R: ciso::read::Read<ReadError = std::io::Error>>(image: &mut R) -> u64 {
+    let mut header = [0; 24];
+    match image.read(0, &mut header).await {
+        Ok(()) => ciso::layout::CSOHeader::deserialize::<std::io::Error>(&header)
+            .map(|header| header.index_table_len() as u64 - 1)
+            .unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Warn if the parts of an image do not seem to form one image
+///
+/// Reading carries on regardless, since blocks that really are missing or
+/// mismatched fail to decode and are reported then.
+#[maybe_async]
+pub async fn validate_parts(reader: &mut SplitImage) {
+    if let Err(e) = reader.validate(None).await {
+        eprintln!("warning: {}", e);
+    }
+}
+
+pub struct SplitStdFs;
+
+pub type BufFile = std::io::BufWriter<std::fs::File>;
+
+#[maybe_async]
+impl ciso::split::SplitFilesystem<std::io::Error, BufFile> for SplitStdFs {
+    async fn create_file(
+        &mut self,
+        path: &std::path::Path,
+        _: Option<u64>,
+    ) -> Result<BufFile, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = std::fs::File::create(path)?;
+        let bf: BufFile = std::io::BufWriter::new(file);
+        Ok(bf)
+    }
+
+    async fn create_new(
+        &mut self,
+        path: &std::path::Path,
+        _: Option<u64>,
+    ) -> Result<BufFile, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        match std::fs::File::create_new(path) {
+            Ok(file) => Ok(std::io::BufWriter::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(std::io::Error::new(
+                e.kind(),
+                format!("{} already exists", path.display()),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn open_file(
+        &mut self,
+        path: &std::path::Path,
+        _: Option<u64>,
+    ) -> Result<Option<BufFile>, std::io::Error> {
+        match std::fs::OpenOptions::new().write(true).open(path) {
+            Ok(file) => Ok(Some(std::io::BufWriter::new(file))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn close(&mut self, _: BufFile) {}
+
+    async fn sync(&mut self, file: &mut BufFile) -> Result<(), std::io::Error> {
+        std::io::Write::flush(file)?;
+        file.get_ref().sync_all()
+    }
+
+    async fn rename(
+        &mut self,
+        from: &std::path::Path,
+        to: &std::path::Path,
+    ) -> Result<(), std::io::Error> {
+        std::fs::rename(from, to)
+    }
+
+    async fn remove(&mut self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        std::fs::remove_file(path)
+    }
+}
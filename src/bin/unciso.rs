@@ -1,51 +1,98 @@
+use ciso::write::AsyncWriter;
 use maybe_async::maybe_async;
-use std::io::Write;
+
+mod cli;
 
 #[cfg_attr(not(feature = "sync"), tokio::main)]
 #[maybe_async]
 async fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let file = std::path::PathBuf::from(&args[1]);
-    let output = file.with_extension("iso");
-
-    let file_ext = file
-        .extension()
-        .and_then(|e| e.to_str())
-        .expect("Input file should have extension");
-    let file_base = file.with_extension("");
-    let split = file_base.extension().is_some_and(|e| e == "1");
-
-    let input: Box<dyn ciso::read::Read<ReadError = std::io::Error>> = if split {
-        let mut files = Vec::new();
-        for i in 1.. {
-            let part = file_base.with_extension(format!("{}.{}", i, file_ext));
-            if !part.exists() {
-                break;
+    let mut salvage = false;
+    // Splitting is opt-in, since most tools expect a single image
+    let mut split_size = ciso::split::SplitSize::None;
+    let mut file = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--salvage" => salvage = true,
+            "--split" => {
+                let arg = args.next().expect("--split needs a size");
+                split_size = cli::parse_split_size(&arg);
             }
-
-            let part = std::fs::File::open(part).unwrap();
-            files.push(part);
+            _ => file = Some(std::path::PathBuf::from(arg)),
         }
+    }
 
-        if files.is_empty() {
-            panic!("File does not exist");
-        }
+    let file = file.expect("Usage: unciso [--salvage] [--split fatx|fat32|none|<bytes>] <image>");
+    let output_name = cli::image_base(&file).with_extension("iso");
 
-        Box::from(ciso::split::SplitFileReader::new(files).await.unwrap())
-    } else {
-        Box::from(std::fs::File::open(file.clone()).unwrap())
-    };
+    let mut input = cli::open_split_image(&file).await.unwrap();
+    if !salvage {
+        cli::validate_parts(&mut input).await;
+    }
+    let part_sizes = input.part_sizes();
     let mut reader = ciso::read::CSOReader::new(input).await.unwrap();
+    reader.enable_checksum_verification().await.unwrap();
+    if salvage {
+        reader.enable_salvage().await.unwrap();
+    }
+
+    let sidecar = cli::load_sidecar(&file);
+    if let Some(sidecar) = sidecar.as_ref() {
+        sidecar.check_parts(&part_sizes).unwrap();
+        sidecar.check_header(reader.header()).unwrap();
+    }
+    let mut hasher = sidecar.as_ref().map(|_| ciso::hash::Hasher::new());
+
+    let progress = std::sync::Arc::new(std::sync::Mutex::new(cli::ProgressBar::new(
+        reader.file_size(),
+    )));
+    let progress_cb = progress.clone();
+    reader.set_progress_callback(move |p| {
+        let mut progress = progress_cb.lock().unwrap();
+        let status = format!(
+            "{} -> {} {}",
+            cli::format_bytes(p.compressed_bytes),
+            cli::format_bytes(p.uncompressed_bytes),
+            cli::format_rate(p.uncompressed_bytes, progress.elapsed()),
+        );
+        progress.update(p.uncompressed_bytes, &status);
+    });
 
-    let mut output = std::fs::File::create(output).unwrap();
+    // Parts are named `game.1.iso`, `game.2.iso` and so on, as Xbox
+    // loaders expect, or `game.iso` if the image fits in one part
+    let mut output = ciso::split::SplitOutput::new(cli::SplitStdFs, output_name);
+    output.set_extension("iso");
+    output.set_split_size(split_size);
 
     let mut buf = vec![0; 2048].into_boxed_slice();
     let mut bytes_read = 0;
 
     while bytes_read < reader.file_size() {
         reader.read_offset(bytes_read, &mut buf).await.unwrap();
+
+        if let Err(e) = output.atomic_write(bytes_read, &buf).await {
+            output.rollback().await.unwrap();
+            panic!("Writing the image failed: {}", e);
+        }
         bytes_read += buf.len() as u64;
 
-        output.write_all(&buf).unwrap();
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buf);
+        }
+    }
+
+    output.close().await.unwrap();
+    progress.lock().unwrap().finish("");
+
+    for range in reader.bad_sectors() {
+        println!(
+            "Sectors {}-{} could not be read and were replaced with zeros",
+            range.start,
+            range.end - 1
+        );
+    }
+
+    if let (Some(sidecar), Some(hasher)) = (sidecar, hasher) {
+        sidecar.check_digests(&hasher.finish()).unwrap();
     }
 }
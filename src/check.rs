@@ -0,0 +1,539 @@
+use std::fmt::Display;
+
+use maybe_async::maybe_async;
+
+use crate::{index, layout, read};
+
+/// Limits of a family of loaders, used to flag images they may not handle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoaderProfile {
+    /// Xbox loaders reading LZ4 CSO images from FATX drives
+    Xbox,
+    /// PSP loaders reading CSO v2 images from FAT32 memory sticks
+    Psp,
+}
+
+impl LoaderProfile {
+    fn block_size(&self) -> u32 {
+        2048
+    }
+
+    fn max_alignment(&self) -> u8 {
+        match self {
+            Self::Xbox => 2,
+            Self::Psp => 11,
+        }
+    }
+
+    fn supports_deflate(&self) -> bool {
+        match self {
+            Self::Xbox => false,
+            Self::Psp => true,
+        }
+    }
+
+    fn max_part_size(&self) -> u64 {
+        match self {
+            Self::Xbox => crate::split::SplitSize::Fatx.bytes(),
+            Self::Psp => crate::split::SplitSize::Fat32.bytes(),
+        }
+    }
+
+    fn supports_split(&self) -> bool {
+        match self {
+            Self::Xbox => true,
+            Self::Psp => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The first block does not start right after the index table, so the
+    /// header's `uncompressed_size` disagrees with the index table
+    DataStartMismatch {
+        expected: u64,
+        found: u64,
+    },
+    /// The index entry for this sector is before the previous one
+    IndexNotMonotonic {
+        sector: usize,
+    },
+    /// The final index entry does not point at the end of the file
+    FinalEntryMismatch {
+        end: u64,
+        file_size: u64,
+    },
+    /// The last block runs past the final index entry, which earlier
+    /// versions rounded down, so loaders cut off its end
+    UnpaddedFinalBlock {
+        end: u64,
+        file_size: u64,
+    },
+    /// The block is longer than its data plus alignment padding allows
+    BadPadding {
+        sector: usize,
+    },
+    /// The block could not be decoded
+    CorruptBlock {
+        sector: usize,
+    },
+    /// The block does not match its entry in the checksum trailer
+    ChecksumMismatch {
+        sector: usize,
+    },
+    /// The header gives a block size of zero, so nothing else can be checked
+    InvalidBlockSize(u32),
+    /// The header gives an alignment above `layout::MAX_ALIGNMENT`, so
+    /// nothing else can be checked
+    InvalidAlignment(u8),
+
+    UnsupportedBlockSize(u32),
+    UnsupportedAlignment(u8),
+    /// The block uses deflate, which the loader profile cannot read
+    UnsupportedCodec {
+        sector: usize,
+    },
+    PartTooLarge {
+        index: usize,
+        size: u64,
+    },
+    UnsupportedSplit,
+}
+
+impl Issue {
+    /// Whether this is a structural error, rather than a compatibility
+    /// warning for the selected loader profile
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Self::DataStartMismatch { .. }
+                | Self::IndexNotMonotonic { .. }
+                | Self::FinalEntryMismatch { .. }
+                | Self::UnpaddedFinalBlock { .. }
+                | Self::BadPadding { .. }
+                | Self::CorruptBlock { .. }
+                | Self::ChecksumMismatch { .. }
+                | Self::InvalidBlockSize(_)
+                | Self::InvalidAlignment(_)
+        )
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DataStartMismatch { expected, found } => write!(
+                f,
+                "Data starts at {} but the index table ends at {}",
+                found, expected
+            ),
+            Self::IndexNotMonotonic { sector } => {
+                write!(f, "Index entry for sector {} goes backwards", sector)
+            }
+            Self::FinalEntryMismatch { end, file_size } => write!(
+                f,
+                "Final index entry points at {} but the file is {} bytes",
+                end, file_size
+            ),
+            Self::UnpaddedFinalBlock { end, file_size } => write!(
+                f,
+                "Final index entry points at {} but the last block ends at {}, as written by \
+                 earlier versions; repairing the image fixes it",
+                end, file_size
+            ),
+            Self::BadPadding { sector } => {
+                write!(f, "Block at sector {} has a bad stored length", sector)
+            }
+            Self::CorruptBlock { sector } => write!(f, "Corrupt block at sector {}", sector),
+            Self::ChecksumMismatch { sector } => {
+                write!(f, "Checksum mismatch in block at sector {}", sector)
+            }
+            Self::InvalidBlockSize(size) => write!(f, "Invalid block size {}", size),
+            Self::InvalidAlignment(alignment) => write!(f, "Invalid alignment {}", alignment),
+            Self::UnsupportedBlockSize(size) => write!(f, "Unsupported block size {}", size),
+            Self::UnsupportedAlignment(alignment) => {
+                write!(f, "Unsupported alignment {}", alignment)
+            }
+            Self::UnsupportedCodec { sector } => {
+                write!(f, "Block at sector {} uses an unsupported codec", sector)
+            }
+            Self::PartTooLarge { index, size } => {
+                write!(f, "Part {} is too large ({} bytes)", index + 1, size)
+            }
+            Self::UnsupportedSplit => write!(f, "Split images are not supported"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    pub issues: Vec<Issue>,
+}
+
+impl CheckReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(Issue::is_error)
+    }
+}
+
+/// Check the structure of an image and, if a profile is given, its
+/// compatibility with that family of loaders
+///
+/// `part_sizes` lists the size of each file the image is split across.
+#[maybe_async]
+pub async fn check_image<E, R: read::Read<ReadError = E>>(
+    read: &mut R,
+    part_sizes: &[u64],
+    profile: Option<LoaderProfile>,
+    mut progress_callback: impl FnMut(usize),
+) -> Result<CheckReport, layout::Error<E>> {
+    let mut report = CheckReport::default();
+
+    let mut header = [0; 24];
+    read.read(0, &mut header).await?;
+    let header = match layout::CSOHeader::deserialize(&header) {
+        Ok(header) => header,
+        Err(layout::Error::InvalidBlockSize(size)) => {
+            report.issues.push(Issue::InvalidBlockSize(size));
+            return Ok(report);
+        }
+        Err(layout::Error::InvalidAlignment(alignment)) => {
+            report.issues.push(Issue::InvalidAlignment(alignment));
+            return Ok(report);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut index_table = vec![0; header.index_table_len() * 4];
+    read.read(24, &mut index_table).await?;
+    let index_table = index::IndexTable::deserialize(index_table);
+
+    // `deserialize` rejects alignments above `MAX_ALIGNMENT`, so these
+    // shifts cannot overflow
+    let block_size = header.block_size as usize;
+    let align_b = 1u64 << header.alignment;
+    let entry_pos = |idx: usize| {
+        let pos: u32 = index_table[idx].position().into();
+        (pos as u64) << header.alignment
+    };
+
+    let index_end = 24 + 4 * index_table.len() as u64;
+    let data_start = (index_end + align_b - 1) & !(align_b - 1);
+    if entry_pos(0) != data_start {
+        report.issues.push(Issue::DataStartMismatch {
+            expected: data_start,
+            found: entry_pos(0),
+        });
+    }
+
+    let file_size = read.size().await?;
+    let mut sectors = index_table.len() - 1;
+    for sector in 0..sectors {
+        if entry_pos(sector + 1) < entry_pos(sector) {
+            report
+                .issues
+                .push(Issue::IndexNotMonotonic { sector: sector + 1 });
+            // Block lengths cannot be trusted past this point
+            sectors = sector;
+            break;
+        }
+    }
+
+    let end = entry_pos(index_table.len() - 1);
+    // The trailer covers every sector, even those past a bad index entry
+    let sector_count = index_table.len() - 1;
+    let trailer_size = layout::ChecksumTrailer::HEADER_SIZE as u64 + 4 * sector_count as u64;
+    let mut checksums = None;
+    if file_size == end + trailer_size {
+        let mut trailer = vec![0; trailer_size as usize];
+        read.read(end, &mut trailer).await?;
+
+        let (trailer_header, trailer) = trailer.split_at(layout::ChecksumTrailer::HEADER_SIZE);
+        if layout::ChecksumTrailer::deserialize_header(trailer_header.try_into().unwrap())
+            == Some(sector_count)
+        {
+            checksums = Some(layout::ChecksumTrailer::deserialize_checksums(trailer));
+        }
+    }
+
+    let unpadded = checksums.is_none() && file_size > end && file_size - end < align_b;
+    if unpadded {
+        report
+            .issues
+            .push(Issue::UnpaddedFinalBlock { end, file_size });
+    } else if file_size != end && checksums.is_none() {
+        report
+            .issues
+            .push(Issue::FinalEntryMismatch { end, file_size });
+    }
+
+    for sector in 0..sectors {
+        let pos = entry_pos(sector);
+        let mut len = entry_pos(sector + 1) - pos;
+        if unpadded && sector + 1 == sector_count {
+            len += file_size - end;
+        }
+        let lz4 = index_table[sector].compression_type();
+
+        if pos + len > file_size {
+            report.issues.push(Issue::CorruptBlock { sector });
+            continue;
+        }
+
+        let mut data = vec![0; len as usize];
+        read.read(pos, &mut data).await?;
+
+        let padding_ok = read::encoded_len(block_size, lz4, &data).is_some_and(|encoded| {
+            encoded <= data.len() && data.len() - encoded < align_b as usize
+        });
+        if !padding_ok {
+            report.issues.push(Issue::BadPadding { sector });
+        }
+
+        let raw = !lz4 && data.len() >= block_size;
+        let data = if raw { &data[..block_size] } else { &data };
+        match read::decode_block(block_size, lz4, data) {
+            Some(block) => {
+                if checksums
+                    .as_ref()
+                    .is_some_and(|c| c.checksums[sector] != crc32fast::hash(&block))
+                {
+                    report.issues.push(Issue::ChecksumMismatch { sector });
+                }
+            }
+            None => report.issues.push(Issue::CorruptBlock { sector }),
+        }
+
+        if !lz4 && !raw && profile.is_some_and(|p| !p.supports_deflate()) {
+            report.issues.push(Issue::UnsupportedCodec { sector });
+        }
+
+        progress_callback(sector);
+    }
+
+    if let Some(profile) = profile {
+        if header.block_size != profile.block_size() {
+            report
+                .issues
+                .push(Issue::UnsupportedBlockSize(header.block_size));
+        }
+
+        if header.alignment > profile.max_alignment() {
+            report
+                .issues
+                .push(Issue::UnsupportedAlignment(header.alignment));
+        }
+
+        if part_sizes.len() > 1 && !profile.supports_split() {
+            report.issues.push(Issue::UnsupportedSplit);
+        }
+
+        for (index, size) in part_sizes.iter().enumerate() {
+            if *size > profile.max_part_size() {
+                report
+                    .issues
+                    .push(Issue::PartTooLarge { index, size: *size });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::split::tests::{compress, test_image, unpadded_image};
+    use crate::split::SplitSize;
+
+    #[maybe_async]
+    async fn check(cso: Vec<u8>) -> CheckReport {
+        let part_sizes = [cso.len() as u64];
+        check_image(&mut MemFile::new(cso), &part_sizes, None, |_| {})
+            .await
+            .unwrap()
+    }
+
+    #[maybe_async]
+    async fn compressed_image() -> Vec<u8> {
+        let fs = compress(&test_image(32), SplitSize::None, false).await;
+        fs.file("game.cso").unwrap().contents()
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn invalid_header_fields() {
+        let mut cso = compressed_image().await;
+        cso[16..20].fill(0);
+        let report = check(cso).await;
+        assert_eq!(report.issues, vec![Issue::InvalidBlockSize(0)]);
+
+        let mut cso = compressed_image().await;
+        cso[21] = 40;
+        let report = check(cso).await;
+        assert_eq!(report.issues, vec![Issue::InvalidAlignment(40)]);
+        assert!(report.has_errors());
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn unpadded_final_block() {
+        let (_, cso) = unpadded_image().await;
+        let file_size = cso.len() as u64;
+        let report = check(cso).await;
+        assert_eq!(report.issues.len(), 1);
+        assert!(matches!(
+            report.issues[0],
+            Issue::UnpaddedFinalBlock { end, file_size: size } if end < size && size == file_size
+        ));
+    }
+
+    /// Position of a block, from its index entry
+    fn block_pos(cso: &[u8], sector: usize) -> usize {
+        let entry = crate::util::deserialize_u32_le(&cso[(24 + 4 * sector)..][..4]);
+        ((entry & 0x7fff_ffff) as usize) << 2
+    }
+
+    /// Add `delta` alignment units to an index entry
+    fn shift_entry(cso: &mut [u8], sector: usize, delta: i32) {
+        let entry = &mut cso[(24 + 4 * sector)..][..4];
+        let value = crate::util::deserialize_u32_le(entry);
+        crate::util::serialize_u32_le(value.wrapping_add_signed(delta), entry);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn clean_image() {
+        let cso = compressed_image().await;
+        let report = check(cso.clone()).await;
+        assert!(report.issues.is_empty());
+
+        let part_sizes = [cso.len() as u64];
+        let report = check_image(
+            &mut MemFile::new(cso),
+            &part_sizes,
+            Some(LoaderProfile::Xbox),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(report.issues.is_empty());
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn corrupt_block() {
+        // Sector 1 is an LZ4 block, whose size word now runs past its data
+        let mut cso = compressed_image().await;
+        let pos = block_pos(&cso, 1);
+        crate::util::serialize_u32_le(0x7fff_0000, &mut cso[pos..][..4]);
+
+        let report = check(cso).await;
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue::BadPadding { sector: 1 },
+                Issue::CorruptBlock { sector: 1 }
+            ]
+        );
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn damaged_index() {
+        let mut cso = compressed_image().await;
+        let entry = cso[(24 + 4 * 8)..][..4].to_vec();
+        cso[(24 + 4 * 10)..][..4].copy_from_slice(&entry);
+        let report = check(cso).await;
+        assert!(report
+            .issues
+            .contains(&Issue::IndexNotMonotonic { sector: 10 }));
+        assert!(report.has_errors());
+
+        let mut cso = compressed_image().await;
+        let data_start = block_pos(&cso, 0) as u64;
+        shift_entry(&mut cso, 0, 1);
+        let report = check(cso).await;
+        assert_eq!(
+            report.issues[0],
+            Issue::DataStartMismatch {
+                expected: data_start,
+                found: data_start + 4
+            }
+        );
+
+        let mut cso = compressed_image().await;
+        let end = cso.len() as u64;
+        cso.extend_from_slice(&[0; 10]);
+        let report = check(cso).await;
+        assert_eq!(
+            report.issues,
+            vec![Issue::FinalEntryMismatch {
+                end,
+                file_size: end + 10
+            }]
+        );
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn loader_profiles() {
+        let options = crate::write::WriteOptions {
+            codecs: vec![crate::write::BlockCodec::Deflate(9)],
+            ..crate::write::WriteOptions::default()
+        };
+        let mut output = MemFile::new(Vec::new());
+        crate::write::write_ciso_image_with_options(
+            &mut MemFile::new(test_image(32)),
+            &mut output,
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let cso = output.contents();
+
+        // Deflate blocks suit PSP loaders but not Xbox ones
+        let part_sizes = [cso.len() as u64];
+        let report = check_image(
+            &mut MemFile::new(cso.clone()),
+            &part_sizes,
+            Some(LoaderProfile::Xbox),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(report
+            .issues
+            .contains(&Issue::UnsupportedCodec { sector: 0 }));
+
+        // PSP loaders read a single file
+        let part_sizes = [
+            cso.len() as u64 / 2,
+            cso.len() as u64 - cso.len() as u64 / 2,
+        ];
+        let report = check_image(
+            &mut MemFile::new(cso),
+            &part_sizes,
+            Some(LoaderProfile::Psp),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.issues, vec![Issue::UnsupportedSplit]);
+    }
+}
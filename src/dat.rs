@@ -0,0 +1,258 @@
+use std::fmt::{Debug, Display};
+
+use maybe_async::maybe_async;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::{hash, layout, read};
+
+#[derive(Debug)]
+pub enum DatError {
+    XmlError(quick_xml::Error),
+    /// An attribute of a `rom` element could not be parsed
+    InvalidAttribute(String),
+}
+
+impl From<quick_xml::Error> for DatError {
+    fn from(value: quick_xml::Error) -> Self {
+        Self::XmlError(value)
+    }
+}
+
+impl From<quick_xml::events::attributes::AttrError> for DatError {
+    fn from(value: quick_xml::events::attributes::AttrError) -> Self {
+        Self::XmlError(value.into())
+    }
+}
+
+impl Display for DatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::XmlError(e) => Display::fmt(e, f),
+            Self::InvalidAttribute(attr) => write!(f, "Invalid DAT attribute: {}", attr),
+        }
+    }
+}
+
+impl std::error::Error for DatError {}
+
+/// A single dumped file, as listed in a DAT
+#[derive(Clone, Debug, Default)]
+pub struct Rom {
+    pub name: String,
+    pub size: u64,
+    pub crc32: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl Rom {
+    /// Whether the digests match this rom. The size and every hash the DAT
+    /// provides must agree, and at least one hash must be present.
+    pub fn matches(&self, digests: &hash::Digests) -> bool {
+        if self.crc32.is_none() && self.md5.is_none() && self.sha1.is_none() {
+            return false;
+        }
+
+        self.size == digests.size
+            && self.crc32.is_none_or(|crc32| crc32 == digests.crc32)
+            && self.md5.is_none_or(|md5| md5 == digests.md5)
+            && self.sha1.is_none_or(|sha1| sha1 == digests.sha1)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Game {
+    pub name: String,
+    pub roms: Vec<Rom>,
+}
+
+/// Contents of a Logiqx XML DAT file, such as those published by Redump
+/// and No-Intro
+#[derive(Clone, Debug, Default)]
+pub struct Datafile {
+    pub games: Vec<Game>,
+}
+
+fn parse_hex<const N: usize>(attr: &str, value: &str) -> Result<[u8; N], DatError> {
+    let invalid = || DatError::InvalidAttribute(format!("{}=\"{}\"", attr, value));
+    if value.len() != 2 * N {
+        return Err(invalid());
+    }
+
+    let mut out = [0; N];
+    for (idx, byte) in out.iter_mut().enumerate() {
+        let digits = value.get((2 * idx)..(2 * idx + 2)).ok_or_else(invalid)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+
+    Ok(out)
+}
+
+fn parse_name(element: &BytesStart) -> Result<String, DatError> {
+    for attr in element.attributes() {
+        let attr = attr?;
+        if attr.key.as_ref() == b"name" {
+            return Ok(attr.unescape_value()?.into_owned());
+        }
+    }
+
+    Ok(String::new())
+}
+
+fn parse_rom(element: &BytesStart) -> Result<Rom, DatError> {
+    let mut rom = Rom::default();
+
+    for attr in element.attributes() {
+        let attr = attr?;
+        let value = attr.unescape_value()?;
+        match attr.key.as_ref() {
+            b"name" => rom.name = value.into_owned(),
+            b"size" => {
+                rom.size = value
+                    .parse()
+                    .map_err(|_| DatError::InvalidAttribute(format!("size=\"{}\"", value)))?
+            }
+            b"crc" => rom.crc32 = Some(u32::from_be_bytes(parse_hex("crc", &value)?)),
+            b"md5" => rom.md5 = Some(parse_hex("md5", &value)?),
+            b"sha1" => rom.sha1 = Some(parse_hex("sha1", &value)?),
+            _ => {}
+        }
+    }
+
+    Ok(rom)
+}
+
+impl Datafile {
+    pub fn parse(xml: &str) -> Result<Self, DatError> {
+        let mut reader = quick_xml::Reader::from_str(xml);
+        let mut datafile = Datafile::default();
+        let mut game: Option<Game> = None;
+
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) if matches!(e.name().as_ref(), b"game" | b"machine") => {
+                    game = Some(Game {
+                        name: parse_name(&e)?,
+                        roms: Vec::new(),
+                    });
+                }
+                Event::End(e) if matches!(e.name().as_ref(), b"game" | b"machine") => {
+                    datafile.games.extend(game.take());
+                }
+                Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"rom" => {
+                    if let Some(game) = game.as_mut() {
+                        game.roms.push(parse_rom(&e)?);
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        Ok(datafile)
+    }
+
+    /// Find the game and rom matching the given digests
+    pub fn find(&self, digests: &hash::Digests) -> Option<(&Game, &Rom)> {
+        self.games.iter().find_map(|game| {
+            game.roms
+                .iter()
+                .find(|rom| rom.matches(digests))
+                .map(|rom| (game, rom))
+        })
+    }
+}
+
+/// Outcome of checking an image against a DAT
+#[derive(Clone, Debug)]
+pub struct DatVerification<'a> {
+    pub digests: hash::Digests,
+    /// The matching game and rom, or `None` if the image matches nothing
+    pub matched: Option<(&'a Game, &'a Rom)>,
+}
+
+/// Check whether an image decompresses to a dump listed in the DAT
+#[maybe_async]
+pub async fn verify_image<'a, E, R: read::Read<ReadError = E>>(
+    image: &mut read::CSOReader<E, R>,
+    datafile: &'a Datafile,
+    progress_callback: impl FnMut(usize),
+) -> Result<DatVerification<'a>, layout::Error<E>> {
+    let digests = hash::hash_image(image, progress_callback).await?;
+    let matched = datafile.find(&digests);
+
+    Ok(DatVerification { digests, matched })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+	<header>
+		<name>Sample</name>
+	</header>
+	<game name="Alpha &amp; Omega (USA)">
+		<category>Games</category>
+		<description>Alpha &amp; Omega (USA)</description>
+		<rom name="Alpha &amp; Omega (USA).iso" size="3" crc="352441c2" md5="900150983cd24fb0d6963f7d28e17f72" sha1="a9993e364706816aba3e25717850c26c9cd0d89d"/>
+	</game>
+	<machine name="Beta">
+		<rom name="Beta.iso" size="0" crc="00000000"></rom>
+	</machine>
+</datafile>
+"#;
+
+    fn abc() -> hash::Digests {
+        let mut hasher = hash::Hasher::new();
+        hasher.update(b"abc");
+        hasher.finish()
+    }
+
+    #[test]
+    fn parse_sample() {
+        let datafile = Datafile::parse(SAMPLE).unwrap();
+        assert_eq!(datafile.games.len(), 2);
+
+        let game = &datafile.games[0];
+        assert_eq!(game.name, "Alpha & Omega (USA)");
+        assert_eq!(game.roms.len(), 1);
+        let rom = &game.roms[0];
+        assert_eq!(rom.name, "Alpha & Omega (USA).iso");
+        assert_eq!(rom.size, 3);
+        assert_eq!(rom.crc32, Some(0x352441c2));
+        assert_eq!(rom.md5, Some(abc().md5));
+        assert_eq!(rom.sha1, Some(abc().sha1));
+
+        let game = &datafile.games[1];
+        assert_eq!(game.name, "Beta");
+        assert_eq!(game.roms[0].crc32, Some(0));
+        assert_eq!(game.roms[0].md5, None);
+    }
+
+    #[test]
+    fn find_matching_rom() {
+        let datafile = Datafile::parse(SAMPLE).unwrap();
+        let (game, rom) = datafile.find(&abc()).unwrap();
+        assert_eq!(game.name, "Alpha & Omega (USA)");
+        assert_eq!(rom.size, 3);
+
+        let mut digests = abc();
+        digests.sha1[0] ^= 1;
+        assert!(datafile.find(&digests).is_none());
+
+        let empty = hash::Hasher::new().finish();
+        let (game, _) = datafile.find(&empty).unwrap();
+        assert_eq!(game.name, "Beta");
+    }
+
+    #[test]
+    fn invalid_attribute() {
+        let xml =
+            r#"<datafile><game name="x"><rom name="x.iso" size="3" md5="abc"/></game></datafile>"#;
+        let result = Datafile::parse(xml);
+        assert!(matches!(result, Err(DatError::InvalidAttribute(attr)) if attr == "md5=\"abc\""));
+    }
+}
@@ -0,0 +1,1065 @@
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use maybe_async::maybe_async;
+
+use crate::{
+    read,
+    split::{self, SplitFilesystem, SplitReadFilesystem},
+    write::AsyncWriter,
+};
+
+const FATX_MAGIC: [u8; 4] = *b"FATX";
+const SUPERBLOCK_SIZE: u64 = 4096;
+const SECTOR_SIZE: u64 = 512;
+const DIRENT_SIZE: u64 = 64;
+const MAX_NAME_LEN: usize = 42;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const DIRENT_DELETED: u8 = 0xe5;
+const DIRENT_END: u8 = 0xff;
+
+/// Volumes with fewer clusters than this use a 16-bit FAT
+const FAT16_MAX_CLUSTERS: u64 = 0xfff5;
+/// End of a cluster chain, as kept in the in-memory FAT whatever its width
+const FAT_END: u32 = 0xffff_ffff;
+
+/// FATX dates count years from 2000, so this is 2000-01-01
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// Offset and size of the data partition, mounted as `E:`, on a retail Xbox
+/// hard disk
+pub const RETAIL_PARTITION_E: (u64, u64) = (0xabe8_0000, 0x1_312d_6000);
+
+/// Sectors per cluster on retail Xbox partitions, giving 16 KiB clusters
+pub const RETAIL_SECTORS_PER_CLUSTER: u32 = 32;
+
+#[derive(Debug)]
+pub enum FatxError {
+    IoError(std::io::Error),
+    /// The partition does not start with a FATX superblock
+    InvalidSuperblock,
+    NotFound(PathBuf),
+    NotADirectory(PathBuf),
+    IsADirectory(PathBuf),
+    AlreadyExists(PathBuf),
+    /// Names must be 1 to 42 printable ASCII characters
+    InvalidName(String),
+    DiskFull,
+    /// FATX files cannot reach 4 GiB
+    FileTooLarge,
+    /// A read reached past the end of the file
+    OutOfBounds,
+}
+
+impl From<std::io::Error> for FatxError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+impl Display for FatxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => e.fmt(f),
+            Self::InvalidSuperblock => write!(f, "Not a FATX partition"),
+            Self::NotFound(path) => write!(f, "{} not found", path.display()),
+            Self::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            Self::IsADirectory(path) => write!(f, "{} is a directory", path.display()),
+            Self::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+            Self::InvalidName(name) => write!(f, "Invalid FATX name: {}", name),
+            Self::DiskFull => write!(f, "No free clusters left"),
+            Self::FileTooLarge => write!(f, "File too large for FATX"),
+            Self::OutOfBounds => write!(f, "Read past the end of the file"),
+        }
+    }
+}
+
+impl std::error::Error for FatxError {}
+
+impl From<split::OutOfBounds> for FatxError {
+    fn from(_: split::OutOfBounds) -> Self {
+        Self::OutOfBounds
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FatxDirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug)]
+struct DirEntry {
+    name: String,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+}
+
+impl DirEntry {
+    fn deserialize(raw: &[u8; 64]) -> Option<Self> {
+        let name_len = (raw[0] as usize).min(MAX_NAME_LEN);
+        Some(Self {
+            name: String::from_utf8_lossy(&raw[2..(2 + name_len)]).into_owned(),
+            attributes: raw[1],
+            first_cluster: u32::from_le_bytes(raw[44..48].try_into().unwrap()),
+            size: u32::from_le_bytes(raw[48..52].try_into().unwrap()),
+        })
+    }
+
+    fn serialize(&self) -> [u8; 64] {
+        let mut raw = [0; 64];
+        raw[0] = self.name.len() as u8;
+        raw[1] = self.attributes;
+        raw[2..44].fill(0xff);
+        raw[2..(2 + self.name.len())].copy_from_slice(self.name.as_bytes());
+        raw[44..48].copy_from_slice(&self.first_cluster.to_le_bytes());
+        raw[48..52].copy_from_slice(&self.size.to_le_bytes());
+        // Modification, creation and access times, each a time then a date
+        for date in [54, 58, 62] {
+            raw[date..(date + 2)].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        }
+        raw
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Where a directory entry is stored: the first cluster of its directory and
+/// its index within it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EntryLocation {
+    dir_cluster: u32,
+    index: u64,
+}
+
+fn validate_name(name: &str) -> Result<(), FatxError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        && !name.contains(['/', '\\', ':', '*', '?', '"', '<', '>', '|']);
+
+    if valid {
+        Ok(())
+    } else {
+        Err(FatxError::InvalidName(String::from(name)))
+    }
+}
+
+/// Split a path within the partition into names, accepting both `/` and the
+/// Xbox's `\` as separators
+fn path_names(path: &Path) -> Result<Vec<&str>, FatxError> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| FatxError::InvalidName(path.to_string_lossy().into_owned()))?;
+
+    path_str
+        .split(['/', '\\'])
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(|name| validate_name(name).map(|_| name))
+        .collect()
+}
+
+struct Volume<D> {
+    device: D,
+    cluster_size: u64,
+    fat16: bool,
+    fat_offset: u64,
+    data_offset: u64,
+    /// The FAT, kept in memory and written through to the device
+    fat: Vec<u32>,
+    max_cluster: u32,
+    root_cluster: u32,
+    next_free: u32,
+}
+
+impl<D: Read + Write + Seek> Volume<D> {
+    fn layout(
+        device: D,
+        offset: u64,
+        size: u64,
+        sectors_per_cluster: u32,
+        root_cluster: u32,
+    ) -> Result<Self, FatxError> {
+        let cluster_size = sectors_per_cluster as u64 * SECTOR_SIZE;
+        if cluster_size == 0 || size < SUPERBLOCK_SIZE + 2 * cluster_size {
+            return Err(FatxError::InvalidSuperblock);
+        }
+
+        let cluster_count = size / cluster_size;
+        let fat16 = cluster_count < FAT16_MAX_CLUSTERS;
+        let entry_size = if fat16 { 2 } else { 4 };
+        let fat_size = (cluster_count * entry_size).div_ceil(4096) * 4096;
+        if size < SUPERBLOCK_SIZE + fat_size + cluster_size {
+            return Err(FatxError::InvalidSuperblock);
+        }
+
+        // Clusters are numbered from 1, starting right after the FAT
+        let data_clusters = (size - SUPERBLOCK_SIZE - fat_size) / cluster_size;
+        let max_cluster = core::cmp::min(cluster_count - 1, data_clusters) as u32;
+
+        Ok(Self {
+            device,
+            cluster_size,
+            fat16,
+            fat_offset: offset + SUPERBLOCK_SIZE,
+            data_offset: offset + SUPERBLOCK_SIZE + fat_size,
+            fat: vec![0; cluster_count as usize],
+            max_cluster,
+            root_cluster,
+            next_free: 1,
+        })
+    }
+
+    fn open(mut device: D, offset: u64, size: u64) -> Result<Self, FatxError> {
+        let mut superblock = [0; 18];
+        device.seek(SeekFrom::Start(offset))?;
+        device.read_exact(&mut superblock)?;
+        if superblock[0..4] != FATX_MAGIC {
+            return Err(FatxError::InvalidSuperblock);
+        }
+
+        let sectors_per_cluster = u32::from_le_bytes(superblock[8..12].try_into().unwrap());
+        let root_cluster = u32::from_le_bytes(superblock[12..16].try_into().unwrap());
+        let mut volume = Self::layout(device, offset, size, sectors_per_cluster, root_cluster)?;
+        if root_cluster == 0 || root_cluster > volume.max_cluster {
+            return Err(FatxError::InvalidSuperblock);
+        }
+
+        let entry_size = if volume.fat16 { 2 } else { 4 };
+        let mut fat = vec![0; volume.fat.len() * entry_size];
+        volume.read_at(volume.fat_offset, &mut fat)?;
+        for (entry, raw) in volume.fat.iter_mut().zip(fat.chunks_exact(entry_size)) {
+            *entry = if volume.fat16 {
+                match u16::from_le_bytes(raw.try_into().unwrap()) {
+                    value @ 0xfff8.. => 0xffff_0000 | value as u32,
+                    value => value as u32,
+                }
+            } else {
+                u32::from_le_bytes(raw.try_into().unwrap())
+            };
+        }
+
+        Ok(volume)
+    }
+
+    fn format(
+        device: D,
+        offset: u64,
+        size: u64,
+        sectors_per_cluster: u32,
+    ) -> Result<Self, FatxError> {
+        let mut volume = Self::layout(device, offset, size, sectors_per_cluster, 1)?;
+
+        let mut superblock = vec![0xff; SUPERBLOCK_SIZE as usize];
+        superblock[0..4].copy_from_slice(&FATX_MAGIC);
+        superblock[4..8].copy_from_slice(&0u32.to_le_bytes());
+        superblock[8..12].copy_from_slice(&sectors_per_cluster.to_le_bytes());
+        superblock[12..16].copy_from_slice(&volume.root_cluster.to_le_bytes());
+        superblock[16..18].copy_from_slice(&0u16.to_le_bytes());
+        volume.write_at(offset, &superblock)?;
+
+        let fat_size = (volume.data_offset - volume.fat_offset) as usize;
+        volume.write_at(volume.fat_offset, &vec![0; fat_size])?;
+        // The first entry is reserved and holds the media type
+        volume.set_fat(0, 0xffff_fff8)?;
+        volume.set_fat(volume.root_cluster, FAT_END)?;
+        volume.fill_cluster(volume.root_cluster, DIRENT_END)?;
+
+        Ok(volume)
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), FatxError> {
+        self.device.seek(SeekFrom::Start(pos))?;
+        self.device.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), FatxError> {
+        self.device.seek(SeekFrom::Start(pos))?;
+        self.device.write_all(data)?;
+        Ok(())
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 1) * self.cluster_size
+    }
+
+    fn fill_cluster(&mut self, cluster: u32, byte: u8) -> Result<(), FatxError> {
+        let data = vec![byte; self.cluster_size as usize];
+        self.write_at(self.cluster_pos(cluster), &data)
+    }
+
+    fn set_fat(&mut self, cluster: u32, value: u32) -> Result<(), FatxError> {
+        self.fat[cluster as usize] = value;
+        if self.fat16 {
+            let pos = self.fat_offset + 2 * cluster as u64;
+            self.write_at(pos, &(value as u16).to_le_bytes())
+        } else {
+            let pos = self.fat_offset + 4 * cluster as u64;
+            self.write_at(pos, &value.to_le_bytes())
+        }
+    }
+
+    fn is_chain_end(&self, value: u32) -> bool {
+        value == 0 || value > self.max_cluster
+    }
+
+    fn chain(&self, first_cluster: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+        // A corrupt FAT could loop, but no chain is longer than the volume
+        while !self.is_chain_end(cluster) && chain.len() <= self.max_cluster as usize {
+            chain.push(cluster);
+            cluster = self.fat[cluster as usize];
+        }
+        chain
+    }
+
+    fn allocate(&mut self) -> Result<u32, FatxError> {
+        let clusters = (self.next_free..=self.max_cluster).chain(1..self.next_free);
+        for cluster in clusters {
+            if self.fat[cluster as usize] == 0 {
+                self.set_fat(cluster, FAT_END)?;
+                self.next_free = cluster;
+                return Ok(cluster);
+            }
+        }
+
+        Err(FatxError::DiskFull)
+    }
+
+    /// Add a cluster to the end of a chain, which may be empty
+    fn extend_chain(&mut self, chain: &mut Vec<u32>) -> Result<u32, FatxError> {
+        let cluster = self.allocate()?;
+        if let Some(last) = chain.last() {
+            self.set_fat(*last, cluster)?;
+        }
+        chain.push(cluster);
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, first_cluster: u32) -> Result<(), FatxError> {
+        for cluster in self.chain(first_cluster) {
+            self.set_fat(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    fn entry_pos(&self, dir_chain: &[u32], index: u64) -> Option<u64> {
+        let per_cluster = self.cluster_size / DIRENT_SIZE;
+        let cluster = dir_chain.get((index / per_cluster) as usize)?;
+        Some(self.cluster_pos(*cluster) + (index % per_cluster) * DIRENT_SIZE)
+    }
+
+    /// Entries of a directory, with their indices, up to the end marker
+    fn entries(&mut self, dir_cluster: u32) -> Result<Vec<(u64, DirEntry)>, FatxError> {
+        let dir_chain = self.chain(dir_cluster);
+        let mut entries = Vec::new();
+
+        for index in 0.. {
+            let Some(pos) = self.entry_pos(&dir_chain, index) else {
+                break;
+            };
+
+            let mut raw = [0; 64];
+            self.read_at(pos, &mut raw)?;
+            match raw[0] {
+                0 | DIRENT_END => break,
+                DIRENT_DELETED => continue,
+                _ => entries.extend(DirEntry::deserialize(&raw).map(|e| (index, e))),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn find(&mut self, dir_cluster: u32, name: &str) -> Result<Option<(u64, DirEntry)>, FatxError> {
+        Ok(self
+            .entries(dir_cluster)?
+            .into_iter()
+            .find(|(_, entry)| entry.name.eq_ignore_ascii_case(name)))
+    }
+
+    fn write_entry(&mut self, location: EntryLocation, entry: &DirEntry) -> Result<(), FatxError> {
+        let dir_chain = self.chain(location.dir_cluster);
+        let pos = self.entry_pos(&dir_chain, location.index).unwrap();
+        self.write_at(pos, &entry.serialize())
+    }
+
+    fn update_entry(
+        &mut self,
+        location: EntryLocation,
+        first_cluster: u32,
+        size: u32,
+    ) -> Result<(), FatxError> {
+        let dir_chain = self.chain(location.dir_cluster);
+        let pos = self.entry_pos(&dir_chain, location.index).unwrap();
+        let mut fields = [0; 8];
+        fields[0..4].copy_from_slice(&first_cluster.to_le_bytes());
+        fields[4..8].copy_from_slice(&size.to_le_bytes());
+        self.write_at(pos + 44, &fields)
+    }
+
+    fn delete_entry(&mut self, location: EntryLocation) -> Result<(), FatxError> {
+        let dir_chain = self.chain(location.dir_cluster);
+        let pos = self.entry_pos(&dir_chain, location.index).unwrap();
+        self.write_at(pos, &[DIRENT_DELETED])
+    }
+
+    /// Store an entry in the first free slot of a directory, growing it if
+    /// it is full
+    fn add_entry(
+        &mut self,
+        dir_cluster: u32,
+        entry: &DirEntry,
+    ) -> Result<EntryLocation, FatxError> {
+        let mut dir_chain = self.chain(dir_cluster);
+
+        let mut index = 0;
+        loop {
+            let pos = match self.entry_pos(&dir_chain, index) {
+                Some(pos) => pos,
+                None => {
+                    let cluster = self.extend_chain(&mut dir_chain)?;
+                    self.fill_cluster(cluster, DIRENT_END)?;
+                    self.entry_pos(&dir_chain, index).unwrap()
+                }
+            };
+
+            let mut marker = [0];
+            self.read_at(pos, &mut marker)?;
+            if matches!(marker[0], 0 | DIRENT_END | DIRENT_DELETED) {
+                if marker[0] == 0 {
+                    // Some tools end directories with zeros, so the end
+                    // marker has to move along
+                    if let Some(next) = self.entry_pos(&dir_chain, index + 1) {
+                        self.write_at(next, &[DIRENT_END])?;
+                    }
+                }
+
+                let location = EntryLocation { dir_cluster, index };
+                self.write_entry(location, entry)?;
+                return Ok(location);
+            }
+
+            index += 1;
+        }
+    }
+
+    /// Find the directory holding the last name of `path`, creating missing
+    /// directories if asked to
+    fn parent_dir<'a>(
+        &mut self,
+        path: &'a Path,
+        create: bool,
+    ) -> Result<(u32, &'a str), FatxError> {
+        let names = path_names(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Err(FatxError::InvalidName(path.to_string_lossy().into_owned()));
+        };
+
+        let mut dir_cluster = self.root_cluster;
+        for parent in parents {
+            dir_cluster = match self.find(dir_cluster, parent)? {
+                Some((_, entry)) if entry.is_dir() => entry.first_cluster,
+                Some(_) => return Err(FatxError::NotADirectory(path.to_path_buf())),
+                None if create => self.create_dir(dir_cluster, parent)?,
+                None => return Err(FatxError::NotFound(path.to_path_buf())),
+            };
+        }
+
+        Ok((dir_cluster, name))
+    }
+
+    fn create_dir(&mut self, parent_cluster: u32, name: &str) -> Result<u32, FatxError> {
+        let cluster = self.allocate()?;
+        self.fill_cluster(cluster, DIRENT_END)?;
+        self.add_entry(
+            parent_cluster,
+            &DirEntry {
+                name: String::from(name),
+                attributes: ATTR_DIRECTORY,
+                first_cluster: cluster,
+                size: 0,
+            },
+        )?;
+        Ok(cluster)
+    }
+
+    fn dir_cluster(&mut self, path: &Path) -> Result<u32, FatxError> {
+        if path_names(path)?.is_empty() {
+            return Ok(self.root_cluster);
+        }
+
+        let (parent, name) = self.parent_dir(path, false)?;
+        match self.find(parent, name)? {
+            Some((_, entry)) if entry.is_dir() => Ok(entry.first_cluster),
+            Some(_) => Err(FatxError::NotADirectory(path.to_path_buf())),
+            None => Err(FatxError::NotFound(path.to_path_buf())),
+        }
+    }
+
+    /// Find a file, returning its location and entry
+    fn find_file(&mut self, path: &Path) -> Result<Option<(EntryLocation, DirEntry)>, FatxError> {
+        let (dir_cluster, name) = match self.parent_dir(path, false) {
+            Ok(parent) => parent,
+            Err(FatxError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        match self.find(dir_cluster, name)? {
+            Some((_, entry)) if entry.is_dir() => Err(FatxError::IsADirectory(path.to_path_buf())),
+            Some((index, entry)) => Ok(Some((EntryLocation { dir_cluster, index }, entry))),
+            None => Ok(None),
+        }
+    }
+
+    fn write_file(
+        &mut self,
+        file: &mut FileState,
+        position: u64,
+        data: &[u8],
+    ) -> Result<(), FatxError> {
+        let end = position + data.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FatxError::FileTooLarge);
+        }
+
+        if position > file.size {
+            // Newly allocated clusters hold stale data, so gaps are zeroed
+            let gap = vec![0; (position - file.size) as usize];
+            self.write_file(file, file.size, &gap)?;
+        }
+
+        let needed = end.div_ceil(self.cluster_size) as usize;
+        while file.clusters.len() < needed {
+            self.extend_chain(&mut file.clusters)?;
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            let pos = position + written as u64;
+            let cluster = file.clusters[(pos / self.cluster_size) as usize];
+            let cluster_offset = pos % self.cluster_size;
+            let to_write = core::cmp::min(
+                data.len() - written,
+                (self.cluster_size - cluster_offset) as usize,
+            );
+
+            let device_pos = self.cluster_pos(cluster) + cluster_offset;
+            self.write_at(device_pos, &data[written..(written + to_write)])?;
+            written += to_write;
+        }
+
+        if end > file.size {
+            file.size = end;
+            let first_cluster = file.clusters.first().copied().unwrap_or(0);
+            self.update_entry(file.location, first_cluster, end as u32)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_file(&mut self, file: &FileState, pos: u64, buf: &mut [u8]) -> Result<(), FatxError> {
+        if pos + buf.len() as u64 > file.size {
+            return Err(FatxError::OutOfBounds);
+        }
+
+        let mut bytes_read = 0;
+        while bytes_read < buf.len() {
+            let pos = pos + bytes_read as u64;
+            let cluster = file.clusters[(pos / self.cluster_size) as usize];
+            let cluster_offset = pos % self.cluster_size;
+            let to_read = core::cmp::min(
+                buf.len() - bytes_read,
+                (self.cluster_size - cluster_offset) as usize,
+            );
+
+            let device_pos = self.cluster_pos(cluster) + cluster_offset;
+            self.read_at(device_pos, &mut buf[bytes_read..(bytes_read + to_read)])?;
+            bytes_read += to_read;
+        }
+
+        Ok(())
+    }
+}
+
+struct FileState {
+    location: EntryLocation,
+    clusters: Vec<u32>,
+    size: u64,
+}
+
+/// A FATX partition inside a disk image, such as the `E:` drive of an Xbox
+/// hard disk image used by xemu
+///
+/// Clones share the same partition, so one clone can be given to a
+/// `split::SplitOutput` and another used to read what it wrote. Paths are
+/// relative to the root of the partition and separated by `/`.
+pub struct FatxFilesystem<D> {
+    volume: Arc<Mutex<Volume<D>>>,
+}
+
+impl<D> Clone for FatxFilesystem<D> {
+    fn clone(&self) -> Self {
+        Self {
+            volume: self.volume.clone(),
+        }
+    }
+}
+
+impl<D: Read + Write + Seek + Send> FatxFilesystem<D> {
+    /// Open the FATX partition of `size` bytes at `offset` in `device`
+    pub fn open(device: D, offset: u64, size: u64) -> Result<Self, FatxError> {
+        Ok(Self {
+            volume: Arc::new(Mutex::new(Volume::open(device, offset, size)?)),
+        })
+    }
+
+    /// Write an empty FATX partition of `size` bytes at `offset` in `device`
+    pub fn format(
+        device: D,
+        offset: u64,
+        size: u64,
+        sectors_per_cluster: u32,
+    ) -> Result<Self, FatxError> {
+        Ok(Self {
+            volume: Arc::new(Mutex::new(Volume::format(
+                device,
+                offset,
+                size,
+                sectors_per_cluster,
+            )?)),
+        })
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> Result<(), FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        if path_names(path)?.is_empty() {
+            return Ok(());
+        }
+
+        let (parent, name) = volume.parent_dir(path, true)?;
+        match volume.find(parent, name)? {
+            Some((_, entry)) if entry.is_dir() => Ok(()),
+            Some(_) => Err(FatxError::NotADirectory(path.to_path_buf())),
+            None => volume.create_dir(parent, name).map(|_| ()),
+        }
+    }
+
+    pub fn read_dir(&self, path: &Path) -> Result<Vec<FatxDirEntry>, FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        let dir_cluster = volume.dir_cluster(path)?;
+        Ok(volume
+            .entries(dir_cluster)?
+            .into_iter()
+            .map(|(_, entry)| FatxDirEntry {
+                is_dir: entry.is_dir(),
+                name: entry.name,
+                size: entry.size,
+            })
+            .collect())
+    }
+
+    /// Flush the underlying device
+    pub fn flush(&self) -> Result<(), FatxError> {
+        self.volume.lock().unwrap().device.flush()?;
+        Ok(())
+    }
+
+    fn handle(&self, location: EntryLocation, entry: &DirEntry) -> FatxFile<D> {
+        let volume = self.volume.lock().unwrap();
+        FatxFile {
+            volume: self.volume.clone(),
+            state: FileState {
+                location,
+                clusters: volume.chain(entry.first_cluster),
+                size: entry.size as u64,
+            },
+        }
+    }
+}
+
+/// A file in a FATX partition
+///
+/// The handle keeps its own copy of the cluster chain, so a file should not
+/// be written through more than one handle at a time.
+pub struct FatxFile<D> {
+    volume: Arc<Mutex<Volume<D>>>,
+    state: FileState,
+}
+
+impl<D> FatxFile<D> {
+    pub fn len(&self) -> u64 {
+        self.state.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.size == 0
+    }
+}
+
+#[maybe_async]
+impl<D: Read + Write + Seek + Send> AsyncWriter for FatxFile<D> {
+    type WriteError = FatxError;
+
+    async fn atomic_write(&mut self, position: u64, data: &[u8]) -> Result<(), FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        volume.write_file(&mut self.state, position, data)
+    }
+}
+
+#[maybe_async]
+impl<D: Read + Write + Seek + Send> read::Read for FatxFile<D> {
+    type ReadError = FatxError;
+
+    async fn size(&mut self) -> Result<u64, FatxError> {
+        Ok(self.state.size)
+    }
+
+    async fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        volume.read_file(&self.state, pos, buf)
+    }
+}
+
+#[maybe_async]
+impl<D: Read + Write + Seek + Send> SplitFilesystem<FatxError, FatxFile<D>> for FatxFilesystem<D> {
+    async fn create_file(&mut self, path: &Path, _: Option<u64>) -> Result<FatxFile<D>, FatxError> {
+        let (location, entry) = {
+            let mut volume = self.volume.lock().unwrap();
+            let (dir_cluster, name) = volume.parent_dir(path, true)?;
+
+            let mut entry = DirEntry {
+                name: String::from(name),
+                attributes: 0,
+                first_cluster: 0,
+                size: 0,
+            };
+            let location = match volume.find(dir_cluster, name)? {
+                Some((_, existing)) if existing.is_dir() => {
+                    return Err(FatxError::IsADirectory(path.to_path_buf()));
+                }
+                Some((index, existing)) => {
+                    volume.free_chain(existing.first_cluster)?;
+                    entry.name = existing.name;
+                    let location = EntryLocation { dir_cluster, index };
+                    volume.update_entry(location, 0, 0)?;
+                    location
+                }
+                None => volume.add_entry(dir_cluster, &entry)?,
+            };
+            (location, entry)
+        };
+
+        Ok(self.handle(location, &entry))
+    }
+
+    async fn create_new(
+        &mut self,
+        path: &Path,
+        index: Option<u64>,
+    ) -> Result<FatxFile<D>, FatxError> {
+        if self.volume.lock().unwrap().find_file(path)?.is_some() {
+            return Err(FatxError::AlreadyExists(path.to_path_buf()));
+        }
+        self.create_file(path, index).await
+    }
+
+    async fn open_file(
+        &mut self,
+        path: &Path,
+        _: Option<u64>,
+    ) -> Result<Option<FatxFile<D>>, FatxError> {
+        let found = self.volume.lock().unwrap().find_file(path)?;
+        Ok(found.map(|(location, entry)| self.handle(location, &entry)))
+    }
+
+    async fn close(&mut self, _: FatxFile<D>) {}
+
+    async fn sync(&mut self, _: &mut FatxFile<D>) -> Result<(), FatxError> {
+        self.flush()
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        let (location, mut entry) = volume
+            .find_file(from)?
+            .ok_or_else(|| FatxError::NotFound(from.to_path_buf()))?;
+
+        // Names are case-insensitive, so `to` may be `from` with a new case
+        if let Some((existing, replaced)) = volume.find_file(to)? {
+            if existing != location {
+                volume.delete_entry(existing)?;
+                volume.free_chain(replaced.first_cluster)?;
+            }
+        }
+
+        let (dir_cluster, name) = volume.parent_dir(to, true)?;
+        entry.name = String::from(name);
+        if dir_cluster == location.dir_cluster {
+            volume.write_entry(location, &entry)
+        } else {
+            volume.add_entry(dir_cluster, &entry)?;
+            volume.delete_entry(location)
+        }
+    }
+
+    async fn remove(&mut self, path: &Path) -> Result<(), FatxError> {
+        let mut volume = self.volume.lock().unwrap();
+        let (location, entry) = volume
+            .find_file(path)?
+            .ok_or_else(|| FatxError::NotFound(path.to_path_buf()))?;
+
+        volume.delete_entry(location)?;
+        volume.free_chain(entry.first_cluster)
+    }
+}
+
+#[maybe_async]
+impl<D: Read + Write + Seek + Send> SplitReadFilesystem<FatxError, FatxFile<D>>
+    for FatxFilesystem<D>
+{
+    async fn list_dir(&mut self, dir: &Path) -> Result<Vec<OsString>, FatxError> {
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| OsString::from(entry.name))
+            .collect())
+    }
+
+    async fn open_read(&mut self, path: &Path) -> Result<FatxFile<D>, FatxError> {
+        let found = self.volume.lock().unwrap().find_file(path)?;
+        let (location, entry) = found.ok_or_else(|| FatxError::NotFound(path.to_path_buf()))?;
+        Ok(self.handle(location, &entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::split::{self, tests, SplitOutput, SplitSize};
+
+    /// Partition offset within the disk, to check that it is honoured
+    const OFFSET: u64 = 8192;
+    const SIZE: u64 = 1024 * 1024;
+
+    fn disk() -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0xaa; (OFFSET + SIZE) as usize])
+    }
+
+    fn free_clusters<D>(fs: &FatxFilesystem<D>) -> usize {
+        let volume = fs.volume.lock().unwrap();
+        (1..=volume.max_cluster)
+            .filter(|cluster| volume.fat[*cluster as usize] == 0)
+            .count()
+    }
+
+    fn names(entries: &[FatxDirEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[maybe_async::maybe_async]
+    async fn read_all<D: Read + Write + Seek + Send>(
+        fs: &mut FatxFilesystem<D>,
+        path: &str,
+    ) -> Vec<u8> {
+        let mut file = fs.open_read(Path::new(path)).await.unwrap();
+        let mut data = vec![0; file.len() as usize];
+        read::Read::read(&mut file, 0, &mut data).await.unwrap();
+        data
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn write_and_reopen() {
+        let mut disk = disk();
+        // 512 byte clusters, so a directory holds 8 entries per cluster
+        let big = pattern(5000);
+        {
+            let mut fs = FatxFilesystem::format(&mut disk, OFFSET, SIZE, 1).unwrap();
+            fs.create_dir_all(Path::new("games/halo")).unwrap();
+
+            let mut file = fs
+                .create_file(Path::new("games/halo/big.bin"), None)
+                .await
+                .unwrap();
+            file.atomic_write(0, &big[..1000]).await.unwrap();
+            file.atomic_write(1000, &big[1000..]).await.unwrap();
+            assert_eq!(file.len(), 5000);
+
+            for index in 0..20 {
+                let name = format!("games\\part{}.bin", index);
+                let mut file = fs.create_file(Path::new(&name), None).await.unwrap();
+                file.atomic_write(0, &[index as u8; 3]).await.unwrap();
+            }
+            fs.flush().unwrap();
+        }
+
+        // The partition is left alone outside its bounds
+        assert!(disk.get_ref()[..OFFSET as usize].iter().all(|b| *b == 0xaa));
+
+        let mut fs = FatxFilesystem::open(&mut disk, OFFSET, SIZE).unwrap();
+        assert_eq!(
+            fs.read_dir(Path::new("")).unwrap(),
+            vec![FatxDirEntry {
+                name: String::from("games"),
+                is_dir: true,
+                size: 0,
+            }]
+        );
+
+        let entries = fs.read_dir(Path::new("games")).unwrap();
+        assert_eq!(entries.len(), 21);
+        assert_eq!(entries[0].name, "halo");
+        assert!(entries[0].is_dir);
+        assert_eq!(entries[20].name, "part19.bin");
+        assert_eq!(entries[20].size, 3);
+
+        let listed = fs.list_dir(Path::new("games/halo")).await.unwrap();
+        assert_eq!(listed, vec![OsString::from("big.bin")]);
+        let data = read_all(&mut fs, "GAMES/Halo/big.bin").await;
+        assert_eq!(data, big);
+        let data = read_all(&mut fs, "games/part13.bin").await;
+        assert_eq!(data, vec![13; 3]);
+
+        let mut file = fs.open_read(Path::new("games/part0.bin")).await.unwrap();
+        let mut buf = [0; 4];
+        let result = read::Read::read(&mut file, 0, &mut buf).await;
+        assert!(matches!(result, Err(FatxError::OutOfBounds)));
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn rename_and_remove() {
+        let mut disk = disk();
+        let mut fs = FatxFilesystem::format(&mut disk, OFFSET, SIZE, 1).unwrap();
+        let empty = free_clusters(&fs);
+
+        let mut file = fs.create_file(Path::new("a.cso"), None).await.unwrap();
+        file.atomic_write(0, &pattern(2000)).await.unwrap();
+        let mut file = fs.create_file(Path::new("b/b.cso"), None).await.unwrap();
+        file.atomic_write(0, &pattern(600)).await.unwrap();
+        // One directory cluster, four clusters for a.cso and two for b.cso
+        assert_eq!(free_clusters(&fs), empty - 7);
+
+        // Changing only the case keeps the file
+        fs.rename(Path::new("a.cso"), Path::new("A.CSO"))
+            .await
+            .unwrap();
+        assert_eq!(names(&fs.read_dir(Path::new("")).unwrap()), ["A.CSO", "b"]);
+        let data = read_all(&mut fs, "a.cso").await;
+        assert_eq!(data, pattern(2000));
+        assert_eq!(free_clusters(&fs), empty - 7);
+
+        // Replacing a file frees its clusters
+        fs.rename(Path::new("A.CSO"), Path::new("b/B.cso"))
+            .await
+            .unwrap();
+        assert_eq!(names(&fs.read_dir(Path::new("")).unwrap()), ["b"]);
+        assert_eq!(names(&fs.read_dir(Path::new("b")).unwrap()), ["B.cso"]);
+        let data = read_all(&mut fs, "b/b.cso").await;
+        assert_eq!(data, pattern(2000));
+        assert_eq!(free_clusters(&fs), empty - 5);
+
+        fs.remove(Path::new("b/b.cso")).await.unwrap();
+        assert!(fs.read_dir(Path::new("b")).unwrap().is_empty());
+        assert_eq!(free_clusters(&fs), empty - 1);
+
+        let result = fs.remove(Path::new("b/b.cso")).await;
+        assert!(matches!(result, Err(FatxError::NotFound(_))));
+        let result = fs.rename(Path::new("b"), Path::new("c")).await;
+        assert!(matches!(result, Err(FatxError::IsADirectory(_))));
+
+        // Freed clusters are reused
+        let mut file = fs.create_file(Path::new("c.cso"), None).await.unwrap();
+        file.atomic_write(0, &pattern(SIZE as usize / 2))
+            .await
+            .unwrap();
+        let data = read_all(&mut fs, "c.cso").await;
+        assert_eq!(data, pattern(SIZE as usize / 2));
+    }
+
+    /// Write a split image into a partition of `size` bytes, then read it
+    /// back, returning whether the partition used a 16-bit FAT
+    #[maybe_async::maybe_async]
+    async fn split_round_trip(size: u64, sectors_per_cluster: u32) -> bool {
+        let image = tests::test_image(64);
+        let split_size = SplitSize::Bytes(16 * 1024);
+
+        let mut disk = Cursor::new(vec![0xaa; (OFFSET + size) as usize]);
+        {
+            let fs = FatxFilesystem::format(&mut disk, OFFSET, size, sectors_per_cluster).unwrap();
+            let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.iso"));
+            output.set_split_size(split_size);
+            output.set_output_dir(PathBuf::from("games/game"));
+
+            let mut input = crate::mem::MemFile::new(image.clone());
+            crate::write::write_ciso_image(&mut input, &mut output, |_| {})
+                .await
+                .unwrap();
+            output.close().await.unwrap();
+            fs.flush().unwrap();
+        }
+
+        let mut fs = FatxFilesystem::open(&mut disk, OFFSET, size).unwrap();
+        let parts = fs.read_dir(Path::new("games/game")).unwrap();
+        assert!(parts.len() > 1);
+        assert!(parts[..parts.len() - 1]
+            .iter()
+            .all(|part| part.size as u64 == split_size.bytes()));
+
+        let mut reader = split::open_split(&mut fs, Path::new("games/game/game.cso"))
+            .await
+            .unwrap();
+        reader.validate(Some(split_size.bytes())).await.unwrap();
+
+        let mut reader = crate::read::CSOReader::new(reader).await.unwrap();
+        let mut decompressed = vec![0; reader.file_size() as usize];
+        reader.read_offset(0, &mut decompressed).await.unwrap();
+        assert_eq!(decompressed, image);
+
+        let fat16 = fs.volume.lock().unwrap().fat16;
+        fat16
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn split_round_trip_fat16() {
+        let fat16 = split_round_trip(SIZE, 4).await;
+        assert!(fat16);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn split_round_trip_fat32() {
+        // Too many 512 byte clusters for a 16-bit FAT
+        let fat16 = split_round_trip(40 * 1024 * 1024, 1).await;
+        assert!(!fat16);
+    }
+}
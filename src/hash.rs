@@ -0,0 +1,133 @@
+use maybe_async::maybe_async;
+use md5::Digest;
+
+use crate::{layout, read};
+
+/// Digests of uncompressed image contents, as catalogued by Redump
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digests {
+    /// Number of bytes hashed
+    pub size: u64,
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Digests {
+    pub fn crc32_hex(&self) -> String {
+        format!("{:08x}", self.crc32)
+    }
+
+    pub fn md5_hex(&self) -> String {
+        hex(&self.md5)
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        hex(&self.sha1)
+    }
+}
+
+/// Incrementally computes `Digests` over a stream of data
+#[derive(Clone, Default)]
+pub struct Hasher {
+    size: u64,
+    crc32: crc32fast::Hasher,
+    md5: md5::Md5,
+    sha1: sha1::Sha1,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.crc32.update(data);
+        self.md5.update(data);
+        self.sha1.update(data);
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests {
+            size: self.size,
+            crc32: self.crc32.finalize(),
+            md5: self.md5.finalize().into(),
+            sha1: self.sha1.finalize().into(),
+        }
+    }
+}
+
+/// Compute the digests of an image's uncompressed contents without writing
+/// them out
+#[maybe_async]
+pub async fn hash_image<E, R: read::Read<ReadError = E>>(
+    image: &mut read::CSOReader<E, R>,
+    mut progress_callback: impl FnMut(usize),
+) -> Result<Digests, layout::Error<E>> {
+    let mut hasher = Hasher::new();
+
+    for sector in 0..image.sector_count() {
+        let data = image.read_sector(sector as u64).await?;
+        hasher.update(&data);
+        progress_callback(sector);
+    }
+
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::split::tests::{compress, test_image};
+    use crate::split::SplitSize;
+
+    #[test]
+    fn known_digests() {
+        let digests = Hasher::new().finish();
+        assert_eq!(digests.size, 0);
+        assert_eq!(digests.crc32_hex(), "00000000");
+        assert_eq!(digests.md5_hex(), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            digests.sha1_hex(),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+
+        // Updates may split the data anywhere
+        let mut hasher = Hasher::new();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        let digests = hasher.finish();
+        assert_eq!(digests.size, 3);
+        assert_eq!(digests.crc32_hex(), "352441c2");
+        assert_eq!(digests.md5_hex(), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            digests.sha1_hex(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn hash_compressed_image() {
+        let image = test_image(64);
+        let fs = compress(&image, SplitSize::None, false).await;
+        let cso = MemFile::new(fs.file("game.cso").unwrap().contents());
+        let mut reader = read::CSOReader::new(cso).await.unwrap();
+
+        let mut sectors = 0;
+        let digests = hash_image(&mut reader, |_| sectors += 1).await.unwrap();
+        assert_eq!(sectors, 64);
+
+        let mut hasher = Hasher::new();
+        hasher.update(&image);
+        assert_eq!(digests, hasher.finish());
+    }
+}
@@ -33,9 +33,14 @@ impl IndexTable {
     }
 
     pub fn serialize(&self) -> Box<[u8]> {
-        let mut output = vec![0; 4 * self.len()];
-        for idx in 0..self.len() {
-            let start_byte = 4 * idx;
+        self.serialize_range(0..self.len())
+    }
+
+    /// Serialize a run of entries, to be written at `24 + 4 * range.start`
+    pub fn serialize_range(&self, range: core::ops::Range<usize>) -> Box<[u8]> {
+        let mut output = vec![0; 4 * range.len()];
+        for (i, idx) in range.enumerate() {
+            let start_byte = 4 * i;
             let end_byte = start_byte + 4;
 
             let bytes = self[idx].raw_value().to_le_bytes();
@@ -4,7 +4,14 @@ use super::util;
 use arbitrary_int::u31;
 use bitbybit::bitfield;
 
-const CISO_MAGIC: u32 = 0x4F534943;
+pub(crate) const CISO_MAGIC: u32 = 0x4F534943;
+const CHECKSUM_MAGIC: u32 = 0x54435243;
+
+/// Largest alignment accepted in a header
+///
+/// Every block is padded to `1 << alignment` bytes, so anything larger
+/// would only waste space, and corrupt values could not be shifted safely.
+pub const MAX_ALIGNMENT: u8 = 16;
 
 #[repr(C)]
 #[repr(packed)]
@@ -24,6 +31,14 @@ pub struct CSOHeader {
 pub enum Error<E> {
     UnsupportedVersion,
     InvalidHeader,
+    /// The header gives a block size of zero
+    InvalidBlockSize(u32),
+    /// The header gives an alignment above `MAX_ALIGNMENT`
+    InvalidAlignment(u8),
+    /// The block starting at the given sector could not be decoded
+    CorruptBlock(u64),
+    /// The block starting at the given sector does not match its checksum
+    ChecksumMismatch(u64),
     Other(E),
 }
 
@@ -38,6 +53,12 @@ impl<E: Display> Display for Error<E> {
         match self {
             Self::UnsupportedVersion => write!(f, "Unsupported CSO version"),
             Self::InvalidHeader => write!(f, "Invalid CSO header"),
+            Self::InvalidBlockSize(size) => write!(f, "Invalid block size {}", size),
+            Self::InvalidAlignment(alignment) => write!(f, "Invalid alignment {}", alignment),
+            Self::CorruptBlock(sector) => write!(f, "Corrupt block at sector {}", sector),
+            Self::ChecksumMismatch(sector) => {
+                write!(f, "Checksum mismatch in block at sector {}", sector)
+            }
             Self::Other(e) => e.fmt(f),
         }
     }
@@ -88,6 +109,14 @@ impl CSOHeader {
             return Err(Error::InvalidHeader);
         }
 
+        if header.block_size == 0 {
+            return Err(Error::InvalidBlockSize(header.block_size));
+        }
+
+        if header.alignment > MAX_ALIGNMENT {
+            return Err(Error::InvalidAlignment(header.alignment));
+        }
+
         Ok(header)
     }
 
@@ -122,3 +151,51 @@ impl CSOHeader {
         (self.uncompressed_size / self.block_size as u64) as usize + 1
     }
 }
+
+impl Default for CSOHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Optional table of per-block CRC32s of the uncompressed data
+///
+/// It is appended after the last block, where loaders that do not know
+/// about it never look. It is laid out as a magic value, the number of
+/// blocks, then one checksum per block, all as little endian `u32`s.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumTrailer {
+    pub checksums: Vec<u32>,
+}
+
+impl ChecksumTrailer {
+    /// Size of the fixed part of the trailer, before the checksums
+    pub const HEADER_SIZE: usize = 8;
+
+    /// Parse the fixed part of a trailer, returning the number of checksums
+    /// that follow it, or `None` if there is no trailer
+    pub fn deserialize_header(header: &[u8; 8]) -> Option<usize> {
+        if util::deserialize_u32_le(&header[0..4]) != CHECKSUM_MAGIC {
+            return None;
+        }
+
+        Some(util::deserialize_u32_le(&header[4..8]) as usize)
+    }
+
+    pub fn deserialize_checksums(data: &[u8]) -> Self {
+        let checksums = data.chunks_exact(4).map(util::deserialize_u32_le).collect();
+        Self { checksums }
+    }
+
+    pub fn serialize(&self) -> Box<[u8]> {
+        let mut out = vec![0; Self::HEADER_SIZE + 4 * self.checksums.len()];
+        util::serialize_u32_le(CHECKSUM_MAGIC, &mut out[0..4]);
+        util::serialize_u32_le(self.checksums.len() as u32, &mut out[4..8]);
+        for (idx, checksum) in self.checksums.iter().enumerate() {
+            let start_byte = Self::HEADER_SIZE + 4 * idx;
+            util::serialize_u32_le(*checksum, &mut out[start_byte..(start_byte + 4)]);
+        }
+
+        out.into_boxed_slice()
+    }
+}
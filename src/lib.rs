@@ -1,6 +1,14 @@
+pub mod check;
+pub mod dat;
+pub mod fatx;
+pub mod hash;
 mod index;
 pub mod layout;
+pub mod mem;
 pub mod read;
+pub mod repair;
+pub mod sidecar;
 pub mod split;
 mod util;
+pub mod verify;
 pub mod write;
@@ -0,0 +1,386 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use maybe_async::maybe_async;
+
+use crate::{
+    read,
+    split::{self, SplitFilesystem, SplitReadFilesystem},
+    write::{AsyncWriter, SectorReader},
+};
+
+#[derive(Debug)]
+pub enum MemError {
+    NotFound(PathBuf),
+    AlreadyExists(PathBuf),
+    /// A read reached past the end of the file
+    OutOfBounds {
+        pos: u64,
+        len: usize,
+        size: u64,
+    },
+}
+
+impl Display for MemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "{} not found", path.display()),
+            Self::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+            Self::OutOfBounds { pos, len, size } => write!(
+                f,
+                "Read of {} bytes at {} is past the end of a {} byte file",
+                len, pos, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MemError {}
+
+impl From<split::OutOfBounds> for MemError {
+    fn from(value: split::OutOfBounds) -> Self {
+        Self::OutOfBounds {
+            pos: value.pos,
+            len: value.len,
+            size: value.size,
+        }
+    }
+}
+
+/// A file held in memory
+///
+/// Clones share the same contents, so a handle given out by `MemFilesystem`
+/// writes into the file the filesystem keeps.
+#[derive(Clone, Debug, Default)]
+pub struct MemFile(Arc<Mutex<Vec<u8>>>);
+
+impl MemFile {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self(Arc::new(Mutex::new(data)))
+    }
+
+    /// A copy of the current contents
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.lock().unwrap().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[maybe_async]
+impl AsyncWriter for MemFile {
+    type WriteError = MemError;
+
+    async fn atomic_write(&mut self, position: u64, data: &[u8]) -> Result<(), MemError> {
+        let mut contents = self.0.lock().unwrap();
+        let start = position as usize;
+        let end = start + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+
+        contents[start..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[maybe_async]
+impl read::Read for MemFile {
+    type ReadError = MemError;
+
+    async fn size(&mut self) -> Result<u64, MemError> {
+        Ok(self.len())
+    }
+
+    async fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), MemError> {
+        let contents = self.0.lock().unwrap();
+        let data = usize::try_from(pos)
+            .ok()
+            .and_then(|start| contents.get(start..start.checked_add(buf.len())?))
+            .ok_or(MemError::OutOfBounds {
+                pos,
+                len: buf.len(),
+                size: contents.len() as u64,
+            })?;
+
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[maybe_async]
+impl SectorReader for MemFile {
+    type ReadError = MemError;
+
+    async fn size(&mut self) -> Result<u64, MemError> {
+        Ok(self.len())
+    }
+
+    async fn read_sector(&mut self, sector: usize, sector_size: u32) -> Result<Vec<u8>, MemError> {
+        let mut buf = vec![0; sector_size as usize];
+        read::Read::read(self, sector as u64 * sector_size as u64, &mut buf).await?;
+        Ok(buf)
+    }
+}
+
+/// A filesystem held in memory, for writing and reading split images without
+/// touching the disk
+///
+/// Clones share the same files, so one clone can be given to a
+/// `split::SplitOutput` and another used to inspect or reopen what it wrote.
+/// Paths are compared after dropping `.` components, and a file without a
+/// parent directory is in the current directory `.`.
+#[derive(Clone, Debug, Default)]
+pub struct MemFilesystem {
+    files: Arc<Mutex<BTreeMap<PathBuf, MemFile>>>,
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect()
+}
+
+impl MemFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, replacing any existing one
+    pub fn insert(&self, path: impl AsRef<Path>, data: Vec<u8>) -> MemFile {
+        let file = MemFile::new(data);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(normalize(path.as_ref()), file.clone());
+        file
+    }
+
+    pub fn file(&self, path: impl AsRef<Path>) -> Option<MemFile> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(&normalize(path.as_ref()))
+            .cloned()
+    }
+
+    /// Paths of every file, in order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[maybe_async]
+impl SplitFilesystem<MemError, MemFile> for MemFilesystem {
+    async fn create_file(&mut self, path: &Path, _: Option<u64>) -> Result<MemFile, MemError> {
+        Ok(self.insert(path, Vec::new()))
+    }
+
+    async fn create_new(&mut self, path: &Path, _: Option<u64>) -> Result<MemFile, MemError> {
+        if self.file(path).is_some() {
+            return Err(MemError::AlreadyExists(path.to_path_buf()));
+        }
+        Ok(self.insert(path, Vec::new()))
+    }
+
+    async fn open_file(
+        &mut self,
+        path: &Path,
+        _: Option<u64>,
+    ) -> Result<Option<MemFile>, MemError> {
+        Ok(self.file(path))
+    }
+
+    async fn close(&mut self, _: MemFile) {}
+
+    async fn sync(&mut self, _: &mut MemFile) -> Result<(), MemError> {
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), MemError> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .remove(&normalize(from))
+            .ok_or_else(|| MemError::NotFound(from.to_path_buf()))?;
+        files.insert(normalize(to), file);
+        Ok(())
+    }
+
+    async fn remove(&mut self, path: &Path) -> Result<(), MemError> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(&normalize(path))
+            .map(|_| ())
+            .ok_or_else(|| MemError::NotFound(path.to_path_buf()))
+    }
+}
+
+#[maybe_async]
+impl SplitReadFilesystem<MemError, MemFile> for MemFilesystem {
+    async fn list_dir(&mut self, dir: &Path) -> Result<Vec<OsString>, MemError> {
+        let dir = normalize(dir);
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(dir.as_path()))
+            .filter_map(|path| path.file_name().map(|name| name.to_os_string()))
+            .collect())
+    }
+
+    async fn open_read(&mut self, path: &Path) -> Result<MemFile, MemError> {
+        self.file(path)
+            .ok_or_else(|| MemError::NotFound(path.to_path_buf()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        split::{tests, SplitOutput, SplitSize},
+        write::{self, CancellationToken, ProgressInfo, WriteOptions},
+    };
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn read_past_end() {
+        let mut file = MemFile::new(vec![1, 2, 3, 4]);
+
+        let mut buf = [0; 2];
+        read::Read::read(&mut file, 2, &mut buf).await.unwrap();
+        assert_eq!(buf, [3, 4]);
+
+        let result = read::Read::read(&mut file, 3, &mut buf).await;
+        assert!(matches!(
+            result,
+            Err(MemError::OutOfBounds {
+                pos: 3,
+                len: 2,
+                size: 4
+            })
+        ));
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn rename_remove_and_list() {
+        let mut fs = MemFilesystem::new();
+        fs.insert("./a.cso", vec![1]);
+        fs.insert("games/b.cso", vec![2]);
+        fs.insert("games/c.cso", vec![3]);
+
+        let listed = fs.list_dir(Path::new("")).await.unwrap();
+        assert_eq!(listed, vec![OsString::from("a.cso")]);
+        let listed = fs.list_dir(Path::new("./games")).await.unwrap();
+        assert_eq!(
+            listed,
+            vec![OsString::from("b.cso"), OsString::from("c.cso")]
+        );
+
+        // Renaming replaces any file already at the destination
+        fs.rename(Path::new("games/b.cso"), Path::new("games/c.cso"))
+            .await
+            .unwrap();
+        assert_eq!(fs.file("games/c.cso").unwrap().contents(), vec![2]);
+        assert!(fs.file("games/b.cso").is_none());
+
+        fs.remove(Path::new("a.cso")).await.unwrap();
+        assert_eq!(fs.paths(), vec![PathBuf::from("games/c.cso")]);
+
+        let result = fs.remove(Path::new("a.cso")).await;
+        assert!(matches!(result, Err(MemError::NotFound(_))));
+        let result = fs.open_read(Path::new("a.cso")).await;
+        assert!(matches!(result, Err(MemError::NotFound(_))));
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn resume_split_image() {
+        let image = tests::test_image(64);
+        let split_size = SplitSize::Bytes(16 * 1024);
+        let expected = tests::compress(&image, split_size, false).await;
+
+        // Stop partway through, after a few checkpoints
+        let fs = MemFilesystem::new();
+        let token = CancellationToken::new();
+        let options = WriteOptions {
+            cancellation: Some(token.clone()),
+            checkpoint_interval: Some(8),
+            ..WriteOptions::default()
+        };
+
+        let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.iso"));
+        output.set_split_size(split_size);
+        let result = write::write_ciso_image_with_options(
+            &mut MemFile::new(image.clone()),
+            &mut output,
+            &options,
+            |info| {
+                if let ProgressInfo::SectorFinished(block) = info {
+                    if block.sector == 36 {
+                        token.cancel();
+                    }
+                }
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(write::CSOCreationError::Cancelled)));
+        output.close().await.unwrap();
+        assert!(fs.paths().len() > 1);
+
+        let mut existing = split::open_split(&mut fs.clone(), Path::new("game.cso"))
+            .await
+            .unwrap();
+        let mut output = SplitOutput::reopen(fs.clone(), PathBuf::from("game.iso"));
+        output.set_split_size(split_size);
+        output.set_existing_parts(&existing.part_sizes());
+
+        let mut resumed_at = None;
+        write::resume_ciso_image(
+            &mut MemFile::new(image.clone()),
+            &mut existing,
+            &mut output,
+            &WriteOptions::default(),
+            |info| {
+                if let ProgressInfo::Resumed(sector) = info {
+                    resumed_at = Some(sector);
+                }
+            },
+        )
+        .await
+        .unwrap();
+        output.close().await.unwrap();
+        assert!(resumed_at.is_some_and(|sector| sector > 0 && sector <= 36));
+
+        assert_eq!(fs.paths(), expected.paths());
+        for path in fs.paths() {
+            assert_eq!(
+                fs.file(&path).unwrap().contents(),
+                expected.file(&path).unwrap().contents()
+            );
+        }
+
+        let decompressed = tests::decompress(&fs, split_size).await;
+        assert_eq!(decompressed, image);
+    }
+}
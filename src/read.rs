@@ -42,10 +42,96 @@ impl<E> Read for Box<dyn Read<ReadError = E>> {
     }
 }
 
+/// Running totals reported after each block is decompressed
+///
+/// Each block is counted once, however many times it is read.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadProgress {
+    pub sector: u64,
+    /// Stored bytes read so far
+    pub compressed_bytes: u64,
+    /// Decompressed bytes produced so far
+    pub uncompressed_bytes: u64,
+}
+
+/// Decode a block as stored in the image, returning `None` if it is corrupt
+///
+/// Blocks without the LZ4 flag that are shorter than the block size are
+/// deflate, otherwise they are stored raw.
+pub(crate) fn decode_block(block_size: usize, lz4: bool, data: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut block = Vec::with_capacity(block_size);
+    if lz4 {
+        // The end mark appended below would otherwise stand in for the end
+        // of a truncated block
+        if encoded_len(block_size, true, data).is_none_or(|len| len > data.len()) {
+            return None;
+        }
+
+        let mut framed = Vec::with_capacity(data.len() + 4 + 7);
+        framed.extend_from_slice(LZ4_HEADER);
+        framed.extend_from_slice(data);
+        framed.extend_from_slice(&[0; 4]);
+
+        let lz4 = lz4_flex::frame::FrameDecoder::new(framed.as_slice());
+        lz4.take(block_size as u64 + 1)
+            .read_to_end(&mut block)
+            .ok()?;
+    } else if data.len() < block_size {
+        let deflate = flate2::read::DeflateDecoder::new(data);
+        deflate
+            .take(block_size as u64 + 1)
+            .read_to_end(&mut block)
+            .ok()?;
+    } else {
+        block.extend_from_slice(&data[..block_size]);
+    }
+
+    (block.len() == block_size).then_some(block)
+}
+
+/// Length of the encoded data at the start of a stored block, excluding any
+/// padding after it, or `None` if it cannot be determined
+pub(crate) fn encoded_len(block_size: usize, lz4: bool, data: &[u8]) -> Option<usize> {
+    use std::io::Read;
+
+    if lz4 {
+        if data.len() < 4 {
+            return None;
+        }
+
+        // A single LZ4 frame block: a size word, with the top bit set for
+        // uncompressed data, followed by the block contents
+        let block_len = crate::util::deserialize_u32_le(&data[0..4]) & 0x7fff_ffff;
+        Some(4 + block_len as usize)
+    } else if data.len() < block_size {
+        let mut deflate = flate2::read::DeflateDecoder::new(data);
+        let mut block = Vec::with_capacity(block_size);
+        deflate.read_to_end(&mut block).ok()?;
+        Some(deflate.total_in() as usize)
+    } else {
+        Some(block_size)
+    }
+}
+
+/// State kept while reading in salvage mode
+struct Salvage {
+    /// Size of the underlying image, past which no block can be read
+    image_size: u64,
+    bad_sectors: Vec<core::ops::Range<u64>>,
+}
+
 pub struct CSOReader<E, R: Read<ReadError = E>> {
     read: R,
     header: layout::CSOHeader,
     index_table: index::IndexTable,
+    progress: ReadProgress,
+    /// Sectors already counted in `progress`
+    reported_sectors: Vec<bool>,
+    progress_callback: Option<Box<dyn FnMut(ReadProgress) + Send + Sync>>,
+    checksums: Option<layout::ChecksumTrailer>,
+    salvage: Option<Salvage>,
 
     err_t: core::marker::PhantomData<E>,
 }
@@ -65,6 +151,15 @@ impl<E, R: Read<ReadError = E>> CSOReader<E, R> {
             read,
             header,
             index_table,
+            progress: ReadProgress {
+                sector: 0,
+                compressed_bytes: 0,
+                uncompressed_bytes: 0,
+            },
+            reported_sectors: vec![false; header.index_table_len() - 1],
+            progress_callback: None,
+            checksums: None,
+            salvage: None,
             err_t: core::marker::PhantomData,
         })
     }
@@ -73,6 +168,201 @@ impl<E, R: Read<ReadError = E>> CSOReader<E, R> {
         self.header.uncompressed_size
     }
 
+    pub fn header(&self) -> &layout::CSOHeader {
+        &self.header
+    }
+
+    /// Number of blocks in the image
+    pub fn sector_count(&self) -> usize {
+        self.index_table.len() - 1
+    }
+
+    /// Read and decompress a single block
+    #[maybe_async]
+    pub async fn read_sector(&mut self, sector: u64) -> Result<Vec<u8>, layout::Error<E>> {
+        let data = match self.read_block(sector).await {
+            Ok(data) => data,
+            Err(e) => match self.salvage.as_mut() {
+                Some(salvage) => {
+                    match salvage.bad_sectors.last_mut() {
+                        Some(range) if range.end == sector => range.end += 1,
+                        Some(range) if range.contains(&sector) => {}
+                        _ => salvage.bad_sectors.push(sector..(sector + 1)),
+                    }
+                    vec![0; self.header.block_size as usize]
+                }
+                None => return Err(e),
+            },
+        };
+
+        let compressed_len = self.block_extent(sector).map_or(0, |(_, len)| len);
+        self.report_progress(sector, compressed_len);
+        Ok(data)
+    }
+
+    /// Switch to salvage mode, where blocks that fail to read, decode or
+    /// match their checksum are returned as zeros instead of an error
+    ///
+    /// The affected sectors are available from `bad_sectors`.
+    #[maybe_async]
+    pub async fn enable_salvage(&mut self) -> Result<(), layout::Error<E>> {
+        let image_size = self.read.size().await?;
+        self.salvage = Some(Salvage {
+            image_size,
+            bad_sectors: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    /// Ranges of sectors replaced with zeros in salvage mode
+    pub fn bad_sectors(&self) -> &[core::ops::Range<u64>] {
+        match self.salvage.as_ref() {
+            Some(salvage) => &salvage.bad_sectors,
+            None => &[],
+        }
+    }
+
+    /// Check that the length a block occupies according to the index table
+    /// matches the length of its encoded data, allowing for alignment padding
+    #[maybe_async]
+    pub async fn stored_length_matches(&mut self, sector: u64) -> Result<bool, layout::Error<E>> {
+        let block_size = self.header.block_size as usize;
+        let lz4 = self.index_table[sector as usize].compression_type();
+        let Ok((sector_pos, data_len)) = self.block_extent(sector) else {
+            return Ok(false);
+        };
+
+        let mut data = vec![0; data_len as usize];
+        self.read.read(sector_pos, &mut data).await?;
+
+        let align_b = 1usize << self.header.alignment;
+        Ok(match encoded_len(block_size, lz4, &data) {
+            Some(len) => len <= data.len() && data.len() - len < align_b,
+            None => false,
+        })
+    }
+
+    /// Load the per-block checksum trailer, if the image has one, and verify
+    /// every block read from then on against it
+    ///
+    /// Returns whether a trailer was found.
+    #[maybe_async]
+    pub async fn enable_checksum_verification(&mut self) -> Result<bool, layout::Error<E>> {
+        let end: u32 = self.index_table[self.index_table.len() - 1].position().into();
+        let end = (end as u64) << self.header.alignment;
+        let size = self.read.size().await?;
+
+        let header_size = layout::ChecksumTrailer::HEADER_SIZE as u64;
+        if size < end + header_size {
+            return Ok(false);
+        }
+
+        let mut trailer_header = [0; layout::ChecksumTrailer::HEADER_SIZE];
+        self.read.read(end, &mut trailer_header).await?;
+        let count = match layout::ChecksumTrailer::deserialize_header(&trailer_header) {
+            Some(count) if count == self.sector_count() => count,
+            _ => return Ok(false),
+        };
+
+        if size < end + header_size + 4 * count as u64 {
+            return Ok(false);
+        }
+
+        let mut checksums = vec![0; 4 * count];
+        self.read.read(end + header_size, &mut checksums).await?;
+        self.checksums = Some(layout::ChecksumTrailer::deserialize_checksums(&checksums));
+
+        Ok(true)
+    }
+
+    /// Receive running totals whenever a block is decompressed
+    pub fn set_progress_callback(
+        &mut self,
+        progress_callback: impl FnMut(ReadProgress) + Send + Sync + 'static,
+    ) {
+        self.progress_callback = Some(Box::new(progress_callback));
+    }
+
+    fn report_progress(&mut self, sector: u64, compressed_len: u32) {
+        self.progress.sector = sector;
+        if !std::mem::replace(&mut self.reported_sectors[sector as usize], true) {
+            self.progress.compressed_bytes += compressed_len as u64;
+            self.progress.uncompressed_bytes += self.header.block_size as u64;
+        }
+
+        if let Some(progress_callback) = self.progress_callback.as_mut() {
+            progress_callback(self.progress);
+        }
+    }
+
+    /// Position and stored length (including padding) of a block, or
+    /// `CorruptBlock` if its index entries are out of order or out of range
+    fn block_extent(&self, sector: u64) -> Result<(u64, u32), layout::Error<E>> {
+        let sector_pos: u32 = self.index_table[sector as usize].position().into();
+        let next_pos: u32 = self.index_table[(sector + 1) as usize].position().into();
+        let align_b = 1u64.checked_shl(self.header.alignment as u32);
+
+        let extent = align_b.and_then(|align_b| {
+            let data_len = (next_pos.checked_sub(sector_pos)? as u64).checked_mul(align_b)?;
+            Some((
+                (sector_pos as u64).checked_mul(align_b)?,
+                u32::try_from(data_len).ok()?,
+            ))
+        });
+        extent.ok_or(layout::Error::CorruptBlock(sector))
+    }
+
+    #[maybe_async]
+    async fn read_block(&mut self, sector: u64) -> Result<Vec<u8>, layout::Error<E>> {
+        let block_size = self.header.block_size as usize;
+        let lz4 = self.index_table[sector as usize].compression_type();
+        let (sector_pos, data_len) = self.block_extent(sector)?;
+
+        if self
+            .salvage
+            .as_ref()
+            .is_some_and(|s| sector_pos.saturating_add(data_len as u64) > s.image_size)
+        {
+            return Err(layout::Error::CorruptBlock(sector));
+        }
+
+        let read_len = if !lz4 && data_len as usize >= block_size {
+            block_size
+        } else {
+            data_len as usize
+        };
+        let mut data = vec![0; read_len];
+        self.read.read(sector_pos, &mut data).await?;
+
+        let mut decoded = decode_block(block_size, lz4, &data);
+        if decoded.is_none() && sector + 1 == self.sector_count() as u64 {
+            // Images from earlier versions rounded the final index entry
+            // down, cutting off up to an alignment unit of the last block
+            let end = sector_pos + read_len as u64;
+            let image_size = self.read.size().await?;
+            let extra = image_size
+                .saturating_sub(end)
+                .min((1u64 << self.header.alignment) - 1);
+            if extra > 0 {
+                data.resize(read_len + extra as usize, 0);
+                self.read.read(end, &mut data[read_len..]).await?;
+                let raw = !lz4 && data.len() >= block_size;
+                let stored = if raw { &data[..block_size] } else { &data };
+                decoded = decode_block(block_size, lz4, stored);
+            }
+        }
+        let data = decoded.ok_or(layout::Error::CorruptBlock(sector))?;
+
+        if let Some(trailer) = self.checksums.as_ref() {
+            if trailer.checksums[sector as usize] != crc32fast::hash(&data) {
+                return Err(layout::Error::ChecksumMismatch(sector));
+            }
+        }
+
+        Ok(data)
+    }
+
     #[maybe_async]
     pub async fn read_offset(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), layout::Error<E>> {
         let mut sector = pos / (self.header.block_size as u64);
@@ -83,40 +373,13 @@ impl<E, R: Read<ReadError = E>> CSOReader<E, R> {
         let mut buf_pos = 0;
 
         while len_remaining > 0 {
-            let index_entry = self.index_table[sector as usize];
-            let sector_pos = index_entry.position();
-            let data_len = self.index_table[(sector + 1) as usize].position() - sector_pos;
-            let sector_pos: u32 = sector_pos.into();
-            let sector_pos = (sector_pos as u64) << self.header.alignment;
-            let data_len: u32 = data_len.into();
-            let data_len = data_len << self.header.alignment;
-
-            if !index_entry.compression_type() {
-                let to_read = core::cmp::min(len_remaining, data_len as usize);
-                self.read
-                    .read(sector_pos, &mut buf[buf_pos..(buf_pos + to_read)])
-                    .await?;
-                buf_pos += to_read;
-                len_remaining -= to_read;
-            } else {
-                let mut data = vec![0; data_len as usize + 4 + 7];
-                data[0..7].copy_from_slice(LZ4_HEADER);
-                self.read
-                    .read(sector_pos, &mut data[7..(7 + data_len as usize)])
-                    .await?;
-
-                use std::io::Read;
-                let mut lz4 = lz4_flex::frame::FrameDecoder::new(data.as_slice());
-                let mut data = vec![];
-                let read = lz4.read_to_end(&mut data).unwrap();
-                assert_eq!(read, 2048);
-
-                let to_read = core::cmp::min(len_remaining, data.len());
-                let data = &data[position..(position + to_read)];
-                buf[buf_pos..(buf_pos + to_read)].copy_from_slice(data);
-                buf_pos += to_read;
-                len_remaining -= to_read;
-            }
+            let data = self.read_sector(sector).await?;
+
+            let to_read = core::cmp::min(len_remaining, data.len() - position);
+            let data = &data[position..(position + to_read)];
+            buf[buf_pos..(buf_pos + to_read)].copy_from_slice(data);
+            buf_pos += to_read;
+            len_remaining -= to_read;
 
             position = 0;
             sector += 1;
@@ -125,3 +388,92 @@ impl<E, R: Read<ReadError = E>> CSOReader<E, R> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::split::tests::test_image;
+    use crate::write::{self, WriteOptions};
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn checksum_trailer() {
+        let image = test_image(64);
+        let mut output = MemFile::new(Vec::new());
+        let options = WriteOptions {
+            block_checksums: true,
+            ..WriteOptions::default()
+        };
+        write::write_ciso_image_with_options(
+            &mut MemFile::new(image.clone()),
+            &mut output,
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let mut cso = output.contents();
+
+        // Sector 5 holds noise and is stored raw, so a flipped byte still
+        // decodes and only the checksum can catch it
+        let index_table = index::IndexTable::deserialize(cso[24..][..65 * 4].to_vec());
+        assert!(!index_table[5].compression_type());
+        let pos: u32 = index_table[5].position().into();
+        cso[((pos as usize) << 2) + 100] ^= 1;
+
+        let mut reader = CSOReader::new(MemFile::new(cso.clone())).await.unwrap();
+        let data = reader.read_sector(5).await.unwrap();
+        assert_ne!(data, image[5 * 2048..6 * 2048]);
+
+        let mut reader = CSOReader::new(MemFile::new(cso)).await.unwrap();
+        let found = reader.enable_checksum_verification().await.unwrap();
+        assert!(found);
+        let data = reader.read_sector(4).await.unwrap();
+        assert_eq!(data, image[4 * 2048..5 * 2048]);
+
+        let e = reader.read_sector(5).await.unwrap_err();
+        assert!(matches!(e, layout::Error::ChecksumMismatch(5)));
+        assert_eq!(e.to_string(), "Checksum mismatch in block at sector 5");
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn salvage_zero_fills() {
+        let image = test_image(64);
+        let mut output = MemFile::new(Vec::new());
+        write::write_ciso_image(&mut MemFile::new(image.clone()), &mut output, |_| {})
+            .await
+            .unwrap();
+        let mut cso = output.contents();
+
+        // Damage the block for sector 20 and cut the image inside the block
+        // for sector 40
+        let index_table = index::IndexTable::deserialize(cso[24..][..65 * 4].to_vec());
+        let block_pos = |sector: usize| {
+            let pos: u32 = index_table[sector].position().into();
+            (pos as usize) << 2
+        };
+        cso[block_pos(20)..][..4].fill(0xff);
+        cso.truncate(block_pos(40) + 8);
+
+        let mut reader = CSOReader::new(MemFile::new(cso.clone())).await.unwrap();
+        let result = reader.read_sector(20).await;
+        assert!(matches!(result, Err(layout::Error::CorruptBlock(20))));
+
+        let mut reader = CSOReader::new(MemFile::new(cso)).await.unwrap();
+        reader.enable_salvage().await.unwrap();
+        let mut salvaged = vec![0xaa; image.len()];
+        reader.read_offset(0, &mut salvaged).await.unwrap();
+
+        assert_eq!(reader.bad_sectors(), [20..21, 40..64]);
+        assert_eq!(salvaged[..20 * 2048], image[..20 * 2048]);
+        assert!(salvaged[20 * 2048..21 * 2048].iter().all(|b| *b == 0));
+        assert_eq!(salvaged[21 * 2048..40 * 2048], image[21 * 2048..40 * 2048]);
+        assert!(salvaged[40 * 2048..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,437 @@
+use std::fmt::{Debug, Display};
+
+use arbitrary_int::u31;
+use maybe_async::maybe_async;
+
+use crate::{
+    index, layout, read,
+    write::{self, AsyncWriter},
+};
+
+#[derive(Debug)]
+pub enum RepairError<ReadError, WriteError> {
+    ReadError(layout::Error<ReadError>),
+    WriteError(WriteError),
+}
+
+impl<RE: Display, WE: Display> Display for RepairError<RE, WE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadError(e) => e.fmt(f),
+            Self::WriteError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<RE: Display + Debug, WE: Display + Debug> std::error::Error for RepairError<RE, WE> {}
+
+impl<RE, WE> From<layout::Error<RE>> for RepairError<RE, WE> {
+    fn from(value: layout::Error<RE>) -> Self {
+        Self::ReadError(value)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// Number of blocks recovered from the damaged image
+    pub recovered_sectors: usize,
+    /// Number of recovered blocks whose index entry was wrong or missing
+    pub rebuilt_entries: usize,
+    /// Sectors that could not be recovered and were replaced with zeros
+    pub unrecoverable: Vec<core::ops::Range<usize>>,
+}
+
+impl RepairReport {
+    fn mark_unrecoverable(&mut self, sector: usize) {
+        match self.unrecoverable.last_mut() {
+            Some(range) if range.end == sector => range.end += 1,
+            _ => self.unrecoverable.push(sector..(sector + 1)),
+        }
+    }
+}
+
+/// A block found in the damaged image
+struct FoundBlock {
+    lz4: bool,
+    /// Stored data, without padding
+    data: Vec<u8>,
+    /// Position after the block and its padding
+    next: u64,
+}
+
+fn align_up(pos: u64, align_b: u64) -> u64 {
+    (pos + align_b - 1) & !(align_b - 1)
+}
+
+/// Try to decode the block at `pos` as each kind of block in turn, using
+/// the padding up to the next aligned position as a check
+///
+/// Any data can pass for a raw block, so one is only accepted when
+/// `raw_allowed`, because the index says the block is stored uncompressed
+/// or the blocks after it line up.
+fn find_block(
+    data: &[u8],
+    pos: u64,
+    block_size: usize,
+    align_b: u64,
+    raw_allowed: bool,
+) -> Option<FoundBlock> {
+    let padding_ok = |len: usize| {
+        let next = align_up(pos + len as u64, align_b);
+        let padding_end = core::cmp::min((next - pos) as usize, data.len());
+        data[len..padding_end]
+            .iter()
+            .all(|b| *b == 0)
+            .then_some(next)
+    };
+
+    if let Some(len) = read::encoded_len(block_size, true, data) {
+        if len < block_size
+            && len <= data.len()
+            && read::decode_block(block_size, true, &data[..len]).is_some()
+        {
+            if let Some(next) = padding_ok(len) {
+                return Some(FoundBlock {
+                    lz4: true,
+                    data: data[..len].to_vec(),
+                    next,
+                });
+            }
+        }
+    }
+
+    let short = &data[..core::cmp::min(data.len(), block_size - 1)];
+    if let Some(len) = read::encoded_len(block_size, false, short) {
+        if len < short.len() && read::decode_block(block_size, false, &short[..len]).is_some() {
+            if let Some(next) = padding_ok(len) {
+                return Some(FoundBlock {
+                    lz4: false,
+                    data: data[..len].to_vec(),
+                    next,
+                });
+            }
+        }
+    }
+
+    (raw_allowed && data.len() >= block_size).then(|| FoundBlock {
+        lz4: false,
+        data: data[..block_size].to_vec(),
+        next: align_up(pos + block_size as u64, align_b),
+    })
+}
+
+/// Follow the blocks that would come after a raw block at `pos`, each of
+/// which must also be raw if it does not decode, to see where the run ends
+///
+/// Returns the position after the run if it reaches a block that decodes,
+/// a position the damaged index gives for that sector, or the end of the
+/// image, and `None` if it runs past the end of the data.
+#[maybe_async]
+async fn raw_run_end<E, R: read::Read<ReadError = E>>(
+    damaged: &mut R,
+    header: &layout::CSOHeader,
+    old_index: &index::IndexTable,
+    mut sector: usize,
+    mut pos: u64,
+    file_size: u64,
+) -> Result<Option<u64>, layout::Error<E>> {
+    let block_size = header.block_size as usize;
+    let align_b = 1u64 << header.alignment;
+    loop {
+        if pos + block_size as u64 > file_size {
+            return Ok(None);
+        }
+
+        pos = align_up(pos + block_size as u64, align_b);
+        sector += 1;
+        let indexed: u32 = old_index[sector].position().into();
+        if sector == old_index.len() - 1 || (indexed as u64) << header.alignment == pos {
+            return Ok(Some(pos));
+        }
+        if pos >= file_size {
+            return Ok(None);
+        }
+
+        let len = core::cmp::min(file_size - pos, block_size as u64 + align_b);
+        let mut data = vec![0; len as usize];
+        damaged
+            .read(pos, &mut data)
+            .await
+            .map_err(layout::Error::Other)?;
+        if find_block(&data, pos, block_size, align_b, false).is_some() {
+            return Ok(Some(pos));
+        }
+    }
+}
+
+/// Rebuild a damaged or truncated image into `output`
+///
+/// The header must be intact. Blocks are located using the index table
+/// where it can be trusted, and otherwise by trial-decoding the data that
+/// follows the previous block. Sectors that cannot be recovered are
+/// replaced with zeros and listed in the report.
+#[maybe_async]
+pub async fn repair_image<E, R, O>(
+    damaged: &mut R,
+    output: &mut O,
+    mut progress_callback: impl FnMut(usize),
+) -> Result<RepairReport, RepairError<E, O::WriteError>>
+where
+    R: read::Read<ReadError = E>,
+    O: AsyncWriter,
+{
+    let mut report = RepairReport::default();
+
+    let mut header = [0; 24];
+    damaged
+        .read(0, &mut header)
+        .await
+        .map_err(layout::Error::Other)?;
+    let header = layout::CSOHeader::deserialize(&header)?;
+    let file_size = damaged.size().await.map_err(layout::Error::Other)?;
+
+    let index_len = header.index_table_len();
+    let mut old_index = vec![0; index_len * 4];
+    let readable = file_size.saturating_sub(24).min(old_index.len() as u64) as usize;
+    damaged
+        .read(24, &mut old_index[..readable])
+        .await
+        .map_err(layout::Error::Other)?;
+    let old_index = index::IndexTable::deserialize(old_index);
+    let mut index_table = index::IndexTable::new(&header);
+
+    let block_size = header.block_size as usize;
+    let align_b = 1u64 << header.alignment;
+    let entry_pos = |entry: &layout::IndexTableEntry| {
+        let pos: u32 = entry.position().into();
+        (pos as u64) << header.alignment
+    };
+
+    let data_start = align_up(24 + 4 * index_len as u64, align_b);
+    let mut read_pos = data_start;
+    let mut write_pos = data_start;
+    // End of the last run of raw blocks followed, and whether it ended
+    // somewhere that confirms those blocks are raw
+    let mut raw_run: Option<(u64, bool)> = None;
+
+    for sector in 0..(index_len - 1) {
+        let found = if read_pos < file_size {
+            // Enough data for the largest block plus its padding
+            let len = core::cmp::min(file_size - read_pos, block_size as u64 + align_b);
+            let mut data = vec![0; len as usize];
+            damaged
+                .read(read_pos, &mut data)
+                .await
+                .map_err(layout::Error::Other)?;
+
+            // The index entry is trusted if it agrees with where the
+            // previous block ended and its block decodes
+            let entry = old_index[sector];
+            let next = entry_pos(&old_index[sector + 1]);
+            let from_index =
+                if entry_pos(&entry) == read_pos && next > read_pos && next - read_pos <= len {
+                    let stored = &data[..(next - read_pos) as usize];
+                    let stored = if !entry.compression_type() && stored.len() >= block_size {
+                        &stored[..block_size]
+                    } else {
+                        stored
+                    };
+
+                    read::decode_block(block_size, entry.compression_type(), stored).map(|_| {
+                        FoundBlock {
+                            lz4: entry.compression_type(),
+                            data: stored.to_vec(),
+                            next,
+                        }
+                    })
+                } else {
+                    None
+                };
+
+            match from_index {
+                Some(found) => Some(found),
+                None => {
+                    let raw_extent = align_up(block_size as u64, align_b);
+                    let raw_allowed = !entry.compression_type()
+                        && next.checked_sub(entry_pos(&entry)) == Some(raw_extent);
+                    let mut found = find_block(&data, read_pos, block_size, align_b, raw_allowed);
+
+                    // With the index damaged, a raw block is only believed
+                    // if the blocks after it line up
+                    if found.is_none() && data.len() >= block_size {
+                        let confirmed = match raw_run {
+                            Some((end, confirmed)) if read_pos < end => confirmed,
+                            _ => {
+                                let end = raw_run_end(
+                                    damaged, &header, &old_index, sector, read_pos, file_size,
+                                )
+                                .await?;
+                                raw_run = Some((end.unwrap_or(file_size), end.is_some()));
+                                end.is_some()
+                            }
+                        };
+                        if confirmed {
+                            found = find_block(&data, read_pos, block_size, align_b, true);
+                        }
+                    }
+
+                    if found.is_some() {
+                        report.rebuilt_entries += 1;
+                    }
+                    found
+                }
+            }
+        } else {
+            None
+        };
+
+        let (lz4, data) = match found {
+            Some(found) => {
+                report.recovered_sectors += 1;
+                read_pos = found.next;
+                (found.lz4, found.data)
+            }
+            None => {
+                report.mark_unrecoverable(sector);
+                // Carry on from where the index says the next block starts,
+                // if that lies ahead, rather than decoding from the middle
+                // of this one
+                let next = entry_pos(&old_index[sector + 1]);
+                if next > read_pos && next < file_size {
+                    read_pos = next;
+                }
+                (false, vec![0; block_size])
+            }
+        };
+
+        index_table[sector] = layout::IndexTableEntry::default()
+            .with_position(u31::new((write_pos >> header.alignment) as u32))
+            .with_compression_type(lz4);
+        output
+            .atomic_write(write_pos, &data)
+            .await
+            .map_err(RepairError::WriteError)?;
+        write_pos = write::write_padding(output, write_pos + data.len() as u64, align_b)
+            .await
+            .map_err(RepairError::WriteError)?;
+
+        progress_callback(sector);
+    }
+
+    index_table[index_len - 1] = layout::IndexTableEntry::default()
+        .with_position(u31::new((write_pos >> header.alignment) as u32));
+
+    output
+        .atomic_write(0, &header.serialize())
+        .await
+        .map_err(RepairError::WriteError)?;
+    output
+        .atomic_write(24, &index_table.serialize())
+        .await
+        .map_err(RepairError::WriteError)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::split::tests::{compress, test_image};
+    use crate::split::SplitSize;
+
+    #[maybe_async]
+    async fn repair(damaged: Vec<u8>) -> (RepairReport, Vec<u8>) {
+        let mut output = MemFile::new(Vec::new());
+        let report = repair_image(&mut MemFile::new(damaged), &mut output, |_| {})
+            .await
+            .unwrap();
+
+        let mut reader = read::CSOReader::new(output).await.unwrap();
+        let mut image = vec![0; reader.file_size() as usize];
+        reader.read_offset(0, &mut image).await.unwrap();
+        (report, image)
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn zeroed_index() {
+        let image = test_image(64);
+        let fs = compress(&image, SplitSize::None, false).await;
+        let mut cso = fs.file("game.cso").unwrap().contents();
+        cso[24..(24 + 65 * 4)].fill(0);
+
+        let (report, repaired) = repair(cso).await;
+        assert!(report.unrecoverable.is_empty());
+        assert_eq!(report.recovered_sectors, 64);
+        assert_eq!(report.rebuilt_entries, 64);
+        assert_eq!(repaired, image);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn invalid_alignment() {
+        let fs = compress(&test_image(8), SplitSize::None, false).await;
+        let mut cso = fs.file("game.cso").unwrap().contents();
+        cso[21] = 40;
+
+        let mut output = MemFile::new(Vec::new());
+        let result = repair_image(&mut MemFile::new(cso), &mut output, |_| {}).await;
+        assert!(matches!(
+            result,
+            Err(RepairError::ReadError(layout::Error::InvalidAlignment(40)))
+        ));
+        assert_eq!(output.len(), 0);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn truncated_image() {
+        let image = test_image(64);
+        let fs = compress(&image, SplitSize::None, false).await;
+        let mut cso = fs.file("game.cso").unwrap().contents();
+
+        // Cut the image in the middle of the block for sector 40
+        let index_table = index::IndexTable::deserialize(cso[24..][..65 * 4].to_vec());
+        let pos: u32 = index_table[40].position().into();
+        cso.truncate(((pos as usize) << 2) + 8);
+
+        let (report, repaired) = repair(cso).await;
+        assert_eq!(report.recovered_sectors, 40);
+        assert_eq!(report.unrecoverable, vec![40..64]);
+        assert_eq!(repaired[..40 * 2048], image[..40 * 2048]);
+        assert!(repaired[40 * 2048..].iter().all(|b| *b == 0));
+        assert_eq!(repaired.len(), image.len());
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn damaged_block() {
+        let image = test_image(64);
+        let fs = compress(&image, SplitSize::None, false).await;
+        let mut cso = fs.file("game.cso").unwrap().contents();
+
+        // Overwrite the block for sector 20, an LZ4 block of text, so that
+        // it no longer decodes
+        let index_table = index::IndexTable::deserialize(cso[24..][..65 * 4].to_vec());
+        assert!(index_table[20].compression_type());
+        let pos: u32 = index_table[20].position().into();
+        cso[((pos as usize) << 2)..][..4].fill(0xff);
+
+        let (report, repaired) = repair(cso).await;
+        assert_eq!(report.recovered_sectors, 63);
+        assert_eq!(report.rebuilt_entries, 0);
+        assert_eq!(report.unrecoverable, vec![20..21]);
+        assert_eq!(repaired[..20 * 2048], image[..20 * 2048]);
+        assert!(repaired[20 * 2048..21 * 2048].iter().all(|b| *b == 0));
+        assert_eq!(repaired[21 * 2048..], image[21 * 2048..]);
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{hash, layout, write};
+
+/// A part of a compressed image, as recorded in a sidecar
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SidecarPart {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Metadata written alongside a compressed image, such as `game.cso.json`
+///
+/// It records what the image was made from and how, so that mixed-up or
+/// incomplete split sets can be detected when the image is opened.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// File name of the original image
+    pub source_name: Option<String>,
+    pub uncompressed_size: u64,
+    pub compressed_size: u64,
+    pub block_size: u32,
+    pub alignment: u8,
+    /// Codecs the writer was allowed to use, such as `lz4` or `deflate-9`
+    pub codecs: Vec<String>,
+    pub parts: Vec<SidecarPart>,
+
+    pub crc32: Option<String>,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+}
+
+/// Difference between a sidecar and the image it describes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SidecarMismatch {
+    PartCount {
+        expected: usize,
+        found: usize,
+    },
+    PartSize {
+        index: usize,
+        expected: u64,
+        found: u64,
+    },
+    UncompressedSize {
+        expected: u64,
+        found: u64,
+    },
+    BlockSize {
+        expected: u32,
+        found: u32,
+    },
+    Alignment {
+        expected: u8,
+        found: u8,
+    },
+    /// The named digest of the uncompressed data differs
+    Digest(&'static str),
+}
+
+impl Display for SidecarMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PartCount { expected, found } => {
+                write!(f, "Expected {} parts, found {}", expected, found)
+            }
+            Self::PartSize {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Part {} should be {} bytes but is {} bytes",
+                index + 1,
+                expected,
+                found
+            ),
+            Self::UncompressedSize { expected, found } => write!(
+                f,
+                "Image should decompress to {} bytes but decompresses to {} bytes",
+                expected, found
+            ),
+            Self::BlockSize { expected, found } => write!(
+                f,
+                "Image should have {} byte blocks but has {} byte blocks",
+                expected, found
+            ),
+            Self::Alignment { expected, found } => write!(
+                f,
+                "Image should have an alignment of {} but has {}",
+                expected, found
+            ),
+            Self::Digest(name) => write!(f, "{} of the image does not match", name),
+        }
+    }
+}
+
+impl std::error::Error for SidecarMismatch {}
+
+impl Sidecar {
+    /// Describe an image from the report of the write that created it
+    pub fn new(
+        source_name: Option<String>,
+        options: &write::WriteOptions,
+        report: &write::WriteReport,
+        parts: Vec<SidecarPart>,
+    ) -> Self {
+        let codecs = options
+            .codecs
+            .iter()
+            .map(|codec| match codec {
+                write::BlockCodec::Lz4 => String::from("lz4"),
+                write::BlockCodec::Deflate(level) => format!("deflate-{}", level),
+            })
+            .collect();
+
+        let digests = report.digests.as_ref();
+        Self {
+            source_name,
+            uncompressed_size: report.header.uncompressed_size,
+            compressed_size: report.compressed_size,
+            block_size: report.header.block_size,
+            alignment: report.header.alignment,
+            codecs,
+            parts,
+            crc32: digests.map(hash::Digests::crc32_hex),
+            md5: digests.map(hash::Digests::md5_hex),
+            sha1: digests.map(hash::Digests::sha1_hex),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        // Serializing plain data to a string cannot fail
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Check that the parts of an image have the recorded sizes
+    pub fn check_parts(&self, part_sizes: &[u64]) -> Result<(), SidecarMismatch> {
+        if part_sizes.len() != self.parts.len() {
+            return Err(SidecarMismatch::PartCount {
+                expected: self.parts.len(),
+                found: part_sizes.len(),
+            });
+        }
+
+        for (index, (part, size)) in self.parts.iter().zip(part_sizes).enumerate() {
+            if part.size != *size {
+                return Err(SidecarMismatch::PartSize {
+                    index,
+                    expected: part.size,
+                    found: *size,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that an image header agrees with the sidecar
+    pub fn check_header(&self, header: &layout::CSOHeader) -> Result<(), SidecarMismatch> {
+        let uncompressed_size = header.uncompressed_size;
+        if uncompressed_size != self.uncompressed_size {
+            return Err(SidecarMismatch::UncompressedSize {
+                expected: self.uncompressed_size,
+                found: uncompressed_size,
+            });
+        }
+
+        let block_size = header.block_size;
+        if block_size != self.block_size {
+            return Err(SidecarMismatch::BlockSize {
+                expected: self.block_size,
+                found: block_size,
+            });
+        }
+
+        if header.alignment != self.alignment {
+            return Err(SidecarMismatch::Alignment {
+                expected: self.alignment,
+                found: header.alignment,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check the digests of the decompressed image against those recorded
+    pub fn check_digests(&self, digests: &hash::Digests) -> Result<(), SidecarMismatch> {
+        let checks = [
+            ("CRC32", &self.crc32, digests.crc32_hex()),
+            ("MD5", &self.md5, digests.md5_hex()),
+            ("SHA-1", &self.sha1, digests.sha1_hex()),
+        ];
+
+        for (name, expected, found) in checks {
+            if expected
+                .as_ref()
+                .is_some_and(|expected| !expected.eq_ignore_ascii_case(&found))
+            {
+                return Err(SidecarMismatch::Digest(name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{MemFile, MemFilesystem};
+    use crate::split::{tests::test_image, SplitOutput, SplitSize};
+
+    /// Write a split image with a sidecar, returning the sidecar and the
+    /// image's digests
+    #[maybe_async::maybe_async]
+    async fn write_with_sidecar() -> (Sidecar, hash::Digests, MemFilesystem) {
+        let image = test_image(64);
+        let fs = MemFilesystem::new();
+        let mut output = SplitOutput::new(fs.clone(), std::path::PathBuf::from("game.iso"));
+        output.set_split_size(SplitSize::Bytes(16 * 1024));
+
+        let options = write::WriteOptions {
+            compute_hashes: true,
+            ..write::WriteOptions::default()
+        };
+        let report = write::write_ciso_image_with_options(
+            &mut MemFile::new(image),
+            &mut output,
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        let parts = output
+            .parts()
+            .into_iter()
+            .map(|(name, size)| SidecarPart {
+                name: name.to_string_lossy().into_owned(),
+                size,
+            })
+            .collect();
+        let sidecar = Sidecar::new(Some(String::from("game.iso")), &options, &report, parts);
+        output.write_sidecar(&sidecar).await.unwrap();
+        output.close().await.unwrap();
+        (sidecar, report.digests.unwrap(), fs)
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn json_round_trip() {
+        let (sidecar, digests, fs) = write_with_sidecar().await;
+        assert_eq!(sidecar.codecs, vec![String::from("lz4")]);
+        assert_eq!(sidecar.parts[0].name, "game.1.cso");
+        assert_eq!(sidecar.md5, Some(digests.md5_hex()));
+
+        let json = fs.file("game.cso.json").unwrap().contents();
+        let parsed = Sidecar::from_json(std::str::from_utf8(&json).unwrap()).unwrap();
+        assert_eq!(parsed, sidecar);
+        assert_eq!(parsed.check_digests(&digests), Ok(()));
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn mismatches() {
+        let (sidecar, digests, _) = write_with_sidecar().await;
+        let part_sizes: Vec<u64> = sidecar.parts.iter().map(|part| part.size).collect();
+        assert_eq!(sidecar.check_parts(&part_sizes), Ok(()));
+
+        let result = sidecar.check_parts(&part_sizes[1..]);
+        assert_eq!(
+            result,
+            Err(SidecarMismatch::PartCount {
+                expected: part_sizes.len(),
+                found: part_sizes.len() - 1,
+            })
+        );
+
+        let mut wrong_sizes = part_sizes.clone();
+        wrong_sizes[1] += 1;
+        let result = sidecar.check_parts(&wrong_sizes);
+        assert_eq!(
+            result,
+            Err(SidecarMismatch::PartSize {
+                index: 1,
+                expected: part_sizes[1],
+                found: part_sizes[1] + 1,
+            })
+        );
+
+        let mut header = layout::CSOHeader::new();
+        header.uncompressed_size = sidecar.uncompressed_size;
+        assert_eq!(sidecar.check_header(&header), Ok(()));
+        header.alignment = 4;
+        assert_eq!(
+            sidecar.check_header(&header),
+            Err(SidecarMismatch::Alignment {
+                expected: 2,
+                found: 4
+            })
+        );
+        header.uncompressed_size += 2048;
+        assert!(matches!(
+            sidecar.check_header(&header),
+            Err(SidecarMismatch::UncompressedSize { .. })
+        ));
+
+        let mut wrong_digests = digests;
+        wrong_digests.sha1[0] ^= 1;
+        assert_eq!(
+            sidecar.check_digests(&wrong_digests),
+            Err(SidecarMismatch::Digest("SHA-1"))
+        );
+    }
+}
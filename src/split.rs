@@ -1,24 +1,431 @@
 use std::{
-    collections::BTreeMap,
     ffi::{OsStr, OsString},
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
 };
 
 use maybe_async::maybe_async;
 
-use crate::write::AsyncWriter;
+use crate::write::{AsyncWriter, ProgressInfo};
 
 const FILE_SPLIT_POINT: u64 = 0xffbf6000;
+const FAT32_SPLIT_POINT: u64 = 0xfffff800;
+
+/// Size at which output is split into parts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitSize {
+    /// Just under 4 GiB, the largest file FATX can hold
+    #[default]
+    Fatx,
+    /// The largest file FAT32 can hold, rounded down to a whole sector
+    Fat32,
+    /// Never split the output
+    None,
+    /// A custom part size in bytes
+    Bytes(u64),
+}
+
+impl SplitSize {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Self::Fatx => FILE_SPLIT_POINT,
+            Self::Fat32 => FAT32_SPLIT_POINT,
+            Self::None => u64::MAX,
+            Self::Bytes(bytes) => *bytes,
+        }
+    }
+}
+
+/// Builds a part name from the file stem, the extension and the zero-based
+/// part index
+pub type PartNameFn = dyn Fn(&OsStr, &str, u64) -> OsString + Send + Sync;
+
+/// How the parts of a split image are named
+#[derive(Default)]
+pub enum SplitNaming {
+    /// `game.1.cso`, `game.2.cso`, ...
+    #[default]
+    Dotted,
+    /// `game_1.cso`, `game_2.cso`, ...
+    Underscore,
+    /// `game.cso.001`, `game.cso.002`, ...
+    Numbered,
+    /// Build the name with a closure, which may carry state of its own
+    Custom(Box<PartNameFn>),
+}
+
+impl Debug for SplitNaming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dotted => write!(f, "Dotted"),
+            Self::Underscore => write!(f, "Underscore"),
+            Self::Numbered => write!(f, "Numbered"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+impl SplitNaming {
+    /// Name of the part with the given zero-based index
+    pub fn part_name(&self, stem: &OsStr, extension: &str, index: u64) -> OsString {
+        let suffix = match self {
+            Self::Dotted => format!(".{}.{}", index + 1, extension),
+            Self::Underscore => format!("_{}.{}", index + 1, extension),
+            Self::Numbered => format!(".{}.{:03}", extension, index + 1),
+            Self::Custom(name) => return name(stem, extension, index),
+        };
+
+        let mut name = stem.to_os_string();
+        name.push(suffix);
+        name
+    }
+
+    /// Split a part name into its stem, extension and zero-based index, if
+    /// it follows this scheme. Custom schemes cannot be parsed.
+    pub fn parse<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str, u64)> {
+        let (stem, extension, number) = match self {
+            Self::Dotted => {
+                let (rest, extension) = name.rsplit_once('.')?;
+                let (stem, number) = rest.rsplit_once('.')?;
+                (stem, extension, number)
+            }
+            Self::Underscore => {
+                let (rest, extension) = name.rsplit_once('.')?;
+                let (stem, number) = rest.rsplit_once('_')?;
+                (stem, extension, number)
+            }
+            Self::Numbered => {
+                let (rest, number) = name.rsplit_once('.')?;
+                let (stem, extension) = rest.rsplit_once('.')?;
+                if number.len() < 3 {
+                    return None;
+                }
+                (stem, extension, number)
+            }
+            Self::Custom(_) => return None,
+        };
+
+        if stem.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let index = number.parse::<u64>().ok()?.checked_sub(1)?;
+        Some((stem, extension, index))
+    }
+
+    /// The schemes that can be recognised when discovering parts without
+    /// being told how they are named
+    pub const DISCOVERABLE: [SplitNaming; 3] = [Self::Dotted, Self::Underscore, Self::Numbered];
+}
+
+/// Read access to the files of an image, for discovering and opening its parts
+#[maybe_async]
+pub trait SplitReadFilesystem<E, R: crate::read::Read<ReadError = E>>: Send + Sync {
+    /// Names of the files in a directory
+    async fn list_dir(&mut self, dir: &Path) -> Result<Vec<OsString>, E>;
+    async fn open_read(&mut self, path: &Path) -> Result<R, E>;
+}
+
+#[derive(Debug)]
+pub enum SplitOpenError<E> {
+    /// Neither the file nor any parts of a split image exist
+    NotFound,
+    /// The part with this zero-based index is missing, though later parts exist
+    MissingPart(u64),
+    /// The part with this zero-based index starts with the image header,
+    /// which belongs in the first part
+    OutOfOrder(u64),
+    Other(E),
+}
+
+impl<E: Display> Display for SplitOpenError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Image not found"),
+            Self::MissingPart(index) => write!(f, "Part {} is missing", index + 1),
+            Self::OutOfOrder(index) => {
+                write!(
+                    f,
+                    "Part {} holds the image header, parts are out of order",
+                    index + 1
+                )
+            }
+            Self::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Display + Debug> std::error::Error for SplitOpenError<E> {}
+
+/// Find the files making up an image, given its plain name or the name of
+/// any of its parts
+///
+/// `game.cso`, `game.1.cso` and `game.2.cso` all find the parts of a split
+/// image named with any scheme in `SplitNaming::DISCOVERABLE`, or the
+/// single file if the image is not split. Use `find_parts_named` for images
+/// written with another scheme. Exactly one path is returned for
+/// an image that is not split. A file named like a part, such as
+/// `Halo_2.iso`, is opened on its own unless the first part and at least
+/// one other part exist alongside it.
+#[maybe_async]
+pub async fn find_parts<E, R, F>(fs: &mut F, path: &Path) -> Result<Vec<PathBuf>, SplitOpenError<E>>
+where
+    R: crate::read::Read<ReadError = E>,
+    F: SplitReadFilesystem<E, R>,
+{
+    find_parts_named(fs, path, &SplitNaming::default()).await
+}
+
+/// Like `find_parts`, but parts named with `naming` are looked for before
+/// the discoverable schemes
+///
+/// This finds the parts of an image written with `SplitOutput::set_naming`.
+/// Parts named with a `SplitNaming::Custom` scheme cannot be recognised from
+/// their names, so such images must be given by their plain name.
+#[maybe_async]
+pub async fn find_parts_named<E, R, F>(
+    fs: &mut F,
+    path: &Path,
+    naming: &SplitNaming,
+) -> Result<Vec<PathBuf>, SplitOpenError<E>>
+where
+    R: crate::read::Read<ReadError = E>,
+    F: SplitReadFilesystem<E, R>,
+{
+    let discoverable = SplitNaming::DISCOVERABLE;
+    let namings: Vec<&SplitNaming> = std::iter::once(naming).chain(discoverable.iter()).collect();
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or(SplitOpenError::NotFound)?;
+
+    let entries = fs.list_dir(dir).await.map_err(SplitOpenError::Other)?;
+    let entries: Vec<&str> = entries.iter().filter_map(|e| e.to_str()).collect();
+
+    // A part of a split image
+    if let Some((_, parts)) = part_sequence(&entries, name, &namings) {
+        return parts_in_order(dir, parts);
+    }
+
+    if entries.contains(&name) {
+        return Ok(vec![dir.join(name)]);
+    }
+
+    // The base name of a split image
+    if let Some((stem, extension)) = name.rsplit_once('.') {
+        for naming in namings.iter() {
+            if let Some(parts) = collect_parts(&entries, naming, stem, extension) {
+                return parts_in_order(dir, parts);
+            }
+        }
+    }
+
+    // A part name for an image that fit in a single file
+    for naming in namings.iter() {
+        if let Some((stem, extension, _)) = naming.parse(name) {
+            let plain = format!("{}.{}", stem, extension);
+            if entries.contains(&plain.as_str()) {
+                return Ok(vec![dir.join(plain)]);
+            }
+        }
+    }
+
+    Err(SplitOpenError::NotFound)
+}
+
+/// The plain name and parts of the split image that `name` is a part of,
+/// if any
+///
+/// Ordinary files such as `Halo_2.iso` can be named like parts, so `name`
+/// only counts as a part if `entries` holds the first part and at least one
+/// more.
+fn part_sequence<'a>(
+    entries: &[&'a str],
+    name: &str,
+    namings: &[&SplitNaming],
+) -> Option<(String, std::collections::BTreeMap<u64, &'a str>)> {
+    namings.iter().find_map(|naming| {
+        let (stem, extension, _) = naming.parse(name)?;
+        let parts = collect_parts(entries, naming, stem, extension)?;
+        (parts.len() > 1 && parts.contains_key(&0))
+            .then(|| (format!("{}.{}", stem, extension), parts))
+    })
+}
+
+/// Plain name of the split image that `name` is a part of, so that
+/// `game.2.cso` becomes `game.cso`, following the same rules as
+/// `find_parts`
+///
+/// `entries` lists the directory holding `name`. `None` is returned if
+/// `name` is not a part of a split image.
+pub fn split_image_name(entries: &[&str], name: &str) -> Option<String> {
+    let discoverable = SplitNaming::DISCOVERABLE;
+    let namings: Vec<&SplitNaming> = discoverable.iter().collect();
+    part_sequence(entries, name, &namings).map(|(plain, _)| plain)
+}
+
+fn collect_parts<'a>(
+    entries: &[&'a str],
+    naming: &SplitNaming,
+    stem: &str,
+    extension: &str,
+) -> Option<std::collections::BTreeMap<u64, &'a str>> {
+    let parts: std::collections::BTreeMap<u64, &str> = match naming {
+        // Custom names cannot be parsed, so look for each part by name. No
+        // part can have a higher index than there are files, unless earlier
+        // parts are missing.
+        SplitNaming::Custom(_) => (0..entries.len() as u64)
+            .filter_map(|index| {
+                let part = naming.part_name(OsStr::new(stem), extension, index);
+                let entry = entries.iter().find(|entry| **entry == part)?;
+                Some((index, *entry))
+            })
+            .collect(),
+        _ => entries
+            .iter()
+            .filter_map(|entry| match naming.parse(entry) {
+                Some((s, e, index)) if s == stem && e == extension => Some((index, *entry)),
+                _ => None,
+            })
+            .collect(),
+    };
+
+    (!parts.is_empty()).then_some(parts)
+}
+
+fn parts_in_order<E>(
+    dir: &Path,
+    parts: std::collections::BTreeMap<u64, &str>,
+) -> Result<Vec<PathBuf>, SplitOpenError<E>> {
+    let mut paths = Vec::new();
+    for (expected, (index, name)) in parts.into_iter().enumerate() {
+        if index != expected as u64 {
+            return Err(SplitOpenError::MissingPart(expected as u64));
+        }
+        paths.push(dir.join(name));
+    }
+
+    Ok(paths)
+}
+
+/// Open an image given its plain name or the name of any of its parts, as
+/// described for `find_parts`
+///
+/// An error is returned if a later part holds the header of a compressed
+/// image that the first part lacks.
+#[maybe_async]
+pub async fn open_split<E, R, F>(
+    fs: &mut F,
+    path: &Path,
+) -> Result<SplitFileReader<E, R>, SplitOpenError<E>>
+where
+    R: crate::read::Read<ReadError = E>,
+    F: SplitReadFilesystem<E, R>,
+{
+    open_split_named(fs, path, &SplitNaming::default()).await
+}
+
+/// Like `open_split`, but parts named with `naming` are looked for first, as
+/// described for `find_parts_named`
+#[maybe_async]
+pub async fn open_split_named<E, R, F>(
+    fs: &mut F,
+    path: &Path,
+    naming: &SplitNaming,
+) -> Result<SplitFileReader<E, R>, SplitOpenError<E>>
+where
+    R: crate::read::Read<ReadError = E>,
+    F: SplitReadFilesystem<E, R>,
+{
+    let paths = find_parts_named(fs, path, naming).await?;
+
+    let mut readers = Vec::new();
+    for path in paths.iter() {
+        readers.push(fs.open_read(path).await.map_err(SplitOpenError::Other)?);
+    }
+
+    if readers.len() > 1 {
+        let mut header_parts = Vec::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if reader.size().await.map_err(SplitOpenError::Other)? < 4 {
+                continue;
+            }
+
+            let mut magic = [0; 4];
+            reader
+                .read(0, &mut magic)
+                .await
+                .map_err(SplitOpenError::Other)?;
+            if u32::from_le_bytes(magic) == crate::layout::CISO_MAGIC {
+                header_parts.push(index as u64);
+            }
+        }
+
+        // Only checked when the first part lacks the header, since a later
+        // part could start with the same bytes by chance
+        if let Some(index) = header_parts.first().filter(|index| **index != 0) {
+            return Err(SplitOpenError::OutOfOrder(*index));
+        }
+    }
+
+    SplitFileReader::new(readers)
+        .await
+        .map_err(SplitOpenError::Other)
+}
 
 #[maybe_async]
 pub trait SplitFilesystem<E, H: AsyncWriter<WriteError = E>>: Send + Sync {
-    async fn create_file(&mut self, name: &OsStr) -> Result<H, E>;
+    /// Create a file, replacing any existing one
+    ///
+    /// `index` is the zero-based index of the part being created, or `None`
+    /// for other files such as the sidecar.
+    async fn create_file(&mut self, path: &Path, index: Option<u64>) -> Result<H, E>;
+
+    /// Create a file, failing if one already exists
+    ///
+    /// Parts are created this way, so that an existing file with the same
+    /// name, which may be the input, is never truncated.
+    async fn create_new(&mut self, path: &Path, index: Option<u64>) -> Result<H, E>;
+
+    /// Open an existing file for writing without truncating it, returning
+    /// `None` if it does not exist
+    async fn open_file(&mut self, path: &Path, index: Option<u64>) -> Result<Option<H>, E>;
     async fn close(&mut self, file: H);
+
+    /// Flush a file and make sure its contents have reached storage
+    async fn sync(&mut self, file: &mut H) -> Result<(), E>;
+
+    /// Rename a closed file, replacing any file already called `to`
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), E>;
+
+    /// Remove a closed file
+    async fn remove(&mut self, path: &Path) -> Result<(), E>;
 }
 
 pub struct SplitOutput<E: Send + Sync, H: AsyncWriter<WriteError = E>, S: SplitFilesystem<E, H>> {
     fs: S,
-    file_name: std::path::PathBuf,
+    output_dir: PathBuf,
+    stem: OsString,
+    extension: String,
+    naming: SplitNaming,
+    split_size: u64,
+    block_aligned: bool,
+    /// Image position at which each part starts
+    part_starts: Vec<u64>,
     splits: std::collections::BTreeMap<u64, H>,
+    part_sizes: std::collections::BTreeMap<u64, u64>,
+    reopen: bool,
+    /// Whether the first part is under the unsplit name, as it is until a
+    /// second part is needed
+    first_part_unsplit: bool,
+    /// Files created rather than reopened, which are removed on rollback
+    created: Vec<PathBuf>,
+    progress_callback: Option<Box<dyn FnMut(ProgressInfo) + Send + Sync>>,
 
     err_t: core::marker::PhantomData<E>,
 }
@@ -29,42 +436,304 @@ where
     S: SplitFilesystem<E, H>,
     E: Send + Sync,
 {
-    pub fn new(fs: S, file_name: std::path::PathBuf) -> Self {
+    /// Write the parts for `file_name` next to it, so that `/games/x.iso`
+    /// becomes `/games/x.1.cso` and so on, or `/games/x.cso` if it fits in
+    /// one part
+    pub fn new(fs: S, file_name: PathBuf) -> Self {
+        let output_dir = file_name
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let stem = file_name.file_stem().unwrap_or_default().to_os_string();
+
         Self {
             fs,
-            file_name,
+            output_dir,
+            stem,
+            extension: String::from("cso"),
+            naming: SplitNaming::default(),
+            split_size: SplitSize::default().bytes(),
+            block_aligned: false,
+            part_starts: vec![0],
             splits: std::collections::BTreeMap::new(),
+            part_sizes: std::collections::BTreeMap::new(),
+            reopen: false,
+            first_part_unsplit: false,
+            created: Vec::new(),
+            progress_callback: None,
             err_t: core::marker::PhantomData,
         }
     }
 
+    /// Like `new`, but parts that already exist are opened rather than
+    /// replaced, so that an interrupted image can be resumed
+    pub fn reopen(fs: S, file_name: PathBuf) -> Self {
+        Self {
+            reopen: true,
+            ..Self::new(fs, file_name)
+        }
+    }
+
+    /// Set the size at which the output is split, which defaults to the FATX limit
+    ///
+    /// This must be set before anything is written, and panics if the size
+    /// is zero.
+    pub fn set_split_size(&mut self, split_size: SplitSize) {
+        assert!(split_size.bytes() > 0);
+        self.split_size = split_size.bytes();
+    }
+
+    /// End each part early rather than let a compressed block and its
+    /// padding straddle two parts, so parts may be shorter than the split size
+    ///
+    /// Readers see a contiguous image as long as the parts are joined end
+    /// to end, as `SplitFileReader` does. This must be set before anything
+    /// is written.
+    pub fn set_block_aligned(&mut self, block_aligned: bool) {
+        self.block_aligned = block_aligned;
+    }
+
+    /// Record the sizes of parts that already exist, so that resuming a
+    /// block-aligned image keeps their boundaries and `parts` includes parts
+    /// written before the resume
+    ///
+    /// The last part is treated as incomplete.
+    pub fn set_existing_parts(&mut self, part_sizes: &[u64]) {
+        self.part_starts = vec![0];
+        for size in part_sizes.iter().take(part_sizes.len().saturating_sub(1)) {
+            let last = *self.part_starts.last().unwrap();
+            self.part_starts.push(last + size);
+        }
+
+        self.part_sizes = (0..).zip(part_sizes.iter().copied()).collect();
+    }
+
+    /// Write the parts into `output_dir` rather than next to the input
+    ///
+    /// This must be set before anything is written.
+    pub fn set_output_dir(&mut self, output_dir: PathBuf) {
+        self.output_dir = output_dir;
+    }
+
+    /// Set the extension of the parts, which defaults to `cso`
+    ///
+    /// This must be set before anything is written.
+    pub fn set_extension(&mut self, extension: &str) {
+        self.extension = String::from(extension);
+    }
+
+    /// Set how the parts are named, which defaults to `SplitNaming::Dotted`
+    ///
+    /// This must be set before anything is written.
+    pub fn set_naming(&mut self, naming: SplitNaming) {
+        self.naming = naming;
+    }
+
+    /// Receive a `ProgressInfo::SplitPartCreated` event whenever a new part is created
+    pub fn set_progress_callback(
+        &mut self,
+        progress_callback: impl FnMut(ProgressInfo) + Send + Sync + 'static,
+    ) {
+        self.progress_callback = Some(Box::new(progress_callback));
+    }
+
+    /// File names and sizes of the parts written so far
+    pub fn parts(&self) -> Vec<(OsString, u64)> {
+        self.part_sizes
+            .iter()
+            .map(|(index, size)| (self.part_name(*index), *size))
+            .collect()
+    }
+
+    /// Name of the sidecar metadata file, such as `game.cso.json`
+    pub fn sidecar_name(&self) -> OsString {
+        let mut name = self.stem.clone();
+        name.push(format!(".{}.json", self.extension));
+        name
+    }
+
+    /// Write a sidecar metadata file next to the parts
+    #[maybe_async]
+    pub async fn write_sidecar(&mut self, sidecar: &crate::sidecar::Sidecar) -> Result<(), E> {
+        let path = self.output_dir.join(self.sidecar_name());
+        let mut file = self.fs.create_file(&path, None).await?;
+        self.created.push(path);
+        file.atomic_write(0, sidecar.to_json().as_bytes()).await?;
+        self.fs.sync(&mut file).await?;
+        self.fs.close(file).await;
+        Ok(())
+    }
+
     fn split_name(&self, index: u64) -> OsString {
-        self.file_name
-            .with_extension(format!("{}.cso", index + 1))
-            .file_name()
-            .unwrap()
-            .to_os_string()
+        self.naming.part_name(&self.stem, &self.extension, index)
+    }
+
+    fn unsplit_name(&self) -> OsString {
+        let mut name = self.stem.clone();
+        name.push(format!(".{}", self.extension));
+        name
+    }
+
+    /// Current name of a part
+    fn part_name(&self, index: u64) -> OsString {
+        if index == 0 && self.first_part_unsplit {
+            self.unsplit_name()
+        } else {
+            self.split_name(index)
+        }
+    }
+
+    /// Find the part holding `position`, returning its index and the image
+    /// positions it starts and ends at
+    fn part_bounds(&mut self, position: u64) -> (u64, u64, u64) {
+        let split_size = self.split_size;
+        loop {
+            let next = self.part_starts.last().unwrap().saturating_add(split_size);
+            if position < next {
+                break;
+            }
+            self.part_starts.push(next);
+        }
+
+        let index = self.part_starts.partition_point(|start| *start <= position) - 1;
+        let start = self.part_starts[index];
+        let end = match self.part_starts.get(index + 1) {
+            Some(end) => *end,
+            None => start.saturating_add(split_size),
+        };
+        (index as u64, start, end)
     }
 
     #[maybe_async]
-    async fn handle_for_position(&mut self, position: u64) -> Result<&mut H, E> {
-        let index = position / FILE_SPLIT_POINT;
+    async fn handle_for_part(&mut self, index: u64) -> Result<&mut H, E> {
+        if index > 0 {
+            if !self.splits.contains_key(&0) {
+                self.open_part(0).await?;
+            }
+            if self.first_part_unsplit {
+                self.move_first_part().await?;
+            }
+        }
 
-        if self.splits.contains_key(&index) {
-            return Ok(self.splits.get_mut(&index).unwrap());
+        if !self.splits.contains_key(&index) {
+            self.open_part(index).await?;
         }
+        Ok(self.splits.get_mut(&index).unwrap())
+    }
+
+    /// Open a part that is not open yet, creating it unless it is being
+    /// resumed
+    ///
+    /// The first part is created under the unsplit name, which it keeps
+    /// unless a second part is needed.
+    #[maybe_async]
+    async fn open_part(&mut self, index: u64) -> Result<(), E> {
+        let split_path = self.output_dir.join(self.split_name(index));
+        let unsplit_path = self.output_dir.join(self.unsplit_name());
 
-        let file = self.split_name(index);
-        let file = self.fs.create_file(&file).await?;
+        if self.reopen {
+            if let Some(file) = self.fs.open_file(&split_path, Some(index)).await? {
+                self.splits.insert(index, file);
+                return Ok(());
+            }
+
+            if index == 0 {
+                if let Some(file) = self.fs.open_file(&unsplit_path, Some(index)).await? {
+                    self.first_part_unsplit = true;
+                    self.splits.insert(index, file);
+                    return Ok(());
+                }
+            }
+        }
+
+        let path = if index == 0 { unsplit_path } else { split_path };
+        let file = self.fs.create_new(&path, Some(index)).await?;
+        self.first_part_unsplit |= index == 0;
+        self.created.push(path.clone());
         self.splits.insert(index, file);
-        Ok(self.splits.get_mut(&index).unwrap())
+        if let Some(progress_callback) = self.progress_callback.as_mut() {
+            progress_callback(ProgressInfo::SplitPartCreated {
+                index,
+                name: path.into_os_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Rename the first part from the unsplit name to its split name, once
+    /// a second part is needed
+    #[maybe_async]
+    async fn move_first_part(&mut self) -> Result<(), E> {
+        let split_path = self.output_dir.join(self.split_name(0));
+        let unsplit_path = self.output_dir.join(self.unsplit_name());
+
+        // Claim the split name first, so that an existing file with that
+        // name fails the write rather than being replaced by the rename
+        let placeholder = self.fs.create_new(&split_path, Some(0)).await?;
+        self.fs.close(placeholder).await;
+
+        let mut file = self.splits.remove(&0).unwrap();
+        let synced = self.fs.sync(&mut file).await;
+        self.fs.close(file).await;
+        let renamed = match synced {
+            Ok(()) => self.fs.rename(&unsplit_path, &split_path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = renamed {
+            // Reopen the first part where it is, and leave nothing behind
+            // but the files that were there before
+            if let Some(file) = self.fs.open_file(&unsplit_path, Some(0)).await? {
+                self.splits.insert(0, file);
+            }
+            self.fs.remove(&split_path).await?;
+            return Err(e);
+        }
+
+        self.first_part_unsplit = false;
+        if let Some(path) = self.created.iter_mut().find(|path| **path == unsplit_path) {
+            *path = split_path.clone();
+        }
+
+        let file = self.fs.open_file(&split_path, Some(0)).await?;
+        self.splits
+            .insert(0, file.expect("the first part was just renamed"));
+        if let Some(progress_callback) = self.progress_callback.as_mut() {
+            progress_callback(ProgressInfo::SplitPartCreated {
+                index: 0,
+                name: split_path.into_os_string(),
+            });
+        }
+        Ok(())
     }
 
+    /// Sync and close every part
     #[maybe_async]
-    pub async fn close(mut self) {
-        for (_, writer) in self.splits.into_iter() {
+    pub async fn close(mut self) -> Result<(), E> {
+        for (_, mut writer) in std::mem::take(&mut self.splits) {
+            self.fs.sync(&mut writer).await?;
             self.fs.close(writer).await;
         }
+
+        Ok(())
+    }
+
+    /// Close every part and remove the files this output created, after a
+    /// write has failed
+    ///
+    /// Parts that were reopened for resuming are kept, so that the resume
+    /// can be retried. Once a `ProgressInfo::Checkpoint` has been reported
+    /// the parts can be resumed, so they should be kept with `close` instead.
+    #[maybe_async]
+    pub async fn rollback(mut self) -> Result<(), E> {
+        for (_, writer) in std::mem::take(&mut self.splits) {
+            self.fs.close(writer).await;
+        }
+
+        for path in self.created.iter() {
+            self.fs.remove(path).await?;
+        }
+
+        Ok(())
     }
 }
 
@@ -81,66 +750,191 @@ where
         let mut written = 0;
 
         while written < data.len() {
-            let handle = self.handle_for_position(position + written as u64).await?;
-            let bytes_to_split = (position + written as u64) % FILE_SPLIT_POINT;
-            let bytes_to_split = if bytes_to_split == 0 {
-                FILE_SPLIT_POINT
-            } else {
-                bytes_to_split
-            };
-            let to_write = core::cmp::min((data.len() - written) as u64, bytes_to_split);
+            let position = position + written as u64;
+            let (index, start, end) = self.part_bounds(position);
+            let part_offset = position - start;
+
+            let to_write = core::cmp::min((data.len() - written) as u64, end - position);
             assert_ne!(to_write, 0);
 
+            let handle = self.handle_for_part(index).await?;
             handle
-                .atomic_write(
-                    position + written as u64,
-                    &data[written..(written + to_write as usize)],
-                )
+                .atomic_write(part_offset, &data[written..(written + to_write as usize)])
                 .await?;
+
+            let part_size = self.part_sizes.entry(index).or_default();
+            *part_size = core::cmp::max(*part_size, part_offset + to_write);
+
             written += to_write as usize;
         }
 
         Ok(())
     }
+
+    fn keep_together(&mut self, position: u64, len: u64) {
+        if !self.block_aligned {
+            return;
+        }
+
+        let (index, start, end) = self.part_bounds(position);
+        let last_part = index as usize + 1 == self.part_starts.len();
+        if last_part && position > start && position + len > end {
+            self.part_starts.push(position);
+        }
+    }
+}
+
+/// A read reached past the end of the parts given to a `SplitFileReader`
+///
+/// The reader's error type must be convertible from this, which it is for
+/// `std::io::Error` and the errors of the backends in this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfBounds {
+    pub pos: u64,
+    pub len: usize,
+    pub size: u64,
+}
+
+impl Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Read of {} bytes at {} is past the end of the {} byte image; parts may be missing",
+            self.len, self.pos, self.size
+        )
+    }
+}
+
+impl std::error::Error for OutOfBounds {}
+
+impl From<OutOfBounds> for std::io::Error {
+    fn from(value: OutOfBounds) -> Self {
+        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, value)
+    }
 }
 
 pub struct SplitFileReader<E, R: crate::read::Read<ReadError = E>> {
-    files: BTreeMap<u64, R>,
-    last_position: u64,
+    files: Vec<R>,
+    part_sizes: Vec<u64>,
+    legacy: bool,
 
     err_t: core::marker::PhantomData<E>,
 }
 
 impl<E, R: crate::read::Read<ReadError = E>> SplitFileReader<E, R> {
+    /// Sizes of the parts, in order
+    pub fn part_sizes(&self) -> Vec<u64> {
+        self.part_sizes.clone()
+    }
+
+    /// Whether the parts were written by older versions, which stored each
+    /// part at its offset in the whole image rather than from the start of
+    /// the file
+    ///
+    /// Every part of such an image is a sparse file ending at its last
+    /// position in the whole image, so the last part reaches the end of the
+    /// compressed data. Block-aligned parts vary in size, so the end is
+    /// taken from the index table whenever a later part outgrows the first.
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
     #[maybe_async]
     pub async fn new(readers: Vec<R>) -> Result<SplitFileReader<E, R>, E> {
-        let mut position = 0;
-        let mut files = BTreeMap::new();
+        let mut files = Vec::new();
+        let mut part_sizes = Vec::new();
 
         for mut reader in readers {
-            let size = reader.size().await?;
-            files.insert(position, reader);
-            position += size;
+            part_sizes.push(reader.size().await?);
+            files.push(reader);
+        }
+
+        let mut legacy = false;
+        if part_sizes.iter().skip(1).any(|size| *size > part_sizes[0]) {
+            if let Some(data_end) = cso_data_end(&mut files[0], part_sizes[0]).await? {
+                legacy = *part_sizes.last().unwrap() >= data_end;
+            }
         }
 
         Ok(Self {
             files,
-            last_position: position,
+            part_sizes,
+            legacy,
             err_t: core::marker::PhantomData,
         })
     }
+
+    /// Find the part holding `pos`, the offset of `pos` within it and the
+    /// number of bytes the part holds from there
+    ///
+    /// `len` is only used to describe a read that is out of bounds.
+    fn locate(&self, pos: u64, len: usize) -> Result<(usize, u64, u64), OutOfBounds> {
+        if self.legacy {
+            // Each part holds image data at its offset in the whole image,
+            // and the tail of a block straddling a split point was left in
+            // the part before, so the first part reaching past `pos` holds it
+            if let Some(index) = self.part_sizes.iter().position(|size| *size > pos) {
+                return Ok((index, pos, self.part_sizes[index] - pos));
+            }
+        } else {
+            let mut start = 0;
+            for (index, size) in self.part_sizes.iter().enumerate() {
+                if pos < start + size {
+                    return Ok((index, pos - start, start + size - pos));
+                }
+                start += size;
+            }
+        }
+
+        let size = if self.legacy {
+            self.part_sizes.iter().copied().max().unwrap_or(0)
+        } else {
+            self.part_sizes.iter().sum()
+        };
+        Err(OutOfBounds { pos, len, size })
+    }
 }
 
+/// End of the compressed data according to the final index entry, if the
+/// part starts with a CSO header and holds the whole index table
 #[maybe_async]
-impl<E: Send + Sync, R: crate::read::Read<ReadError = E>> crate::read::Read
+async fn cso_data_end<E, R: crate::read::Read<ReadError = E>>(
+    first_part: &mut R,
+    size: u64,
+) -> Result<Option<u64>, E> {
+    if size < 24 {
+        return Ok(None);
+    }
+
+    let mut header = [0; 24];
+    first_part.read(0, &mut header).await?;
+    let Ok(header) = crate::layout::CSOHeader::deserialize::<E>(&header) else {
+        return Ok(None);
+    };
+
+    let entry_pos = 24 + 4 * (header.index_table_len() as u64 - 1);
+    if entry_pos + 4 > size {
+        return Ok(None);
+    }
+
+    let mut entry = [0; 4];
+    first_part.read(entry_pos, &mut entry).await?;
+    let entry = crate::layout::IndexTableEntry::new_with_raw_value(u32::from_le_bytes(entry));
+    let end: u32 = entry.position().into();
+    Ok(Some((end as u64) << header.alignment))
+}
+
+#[maybe_async]
+impl<E: Send + Sync + From<OutOfBounds>, R: crate::read::Read<ReadError = E>> crate::read::Read
     for SplitFileReader<E, R>
 {
     type ReadError = E;
 
     async fn size(&mut self) -> Result<u64, E> {
-        Ok(match self.files.last_entry() {
-            Some(mut entry) => *entry.key() + entry.get_mut().size().await?,
-            None => 0,
+        Ok(if self.legacy {
+            self.part_sizes.iter().copied().max().unwrap_or(0)
+        } else {
+            self.part_sizes.iter().sum()
         })
     }
 
@@ -148,35 +942,567 @@ impl<E: Send + Sync, R: crate::read::Read<ReadError = E>> crate::read::Read
         let mut bytes_read = 0;
 
         while bytes_read < buf.len() {
-            let pos = pos + bytes_read as u64;
+            let remaining = buf.len() - bytes_read;
+            let (index, offset, available) = self.locate(pos + bytes_read as u64, remaining)?;
 
-            let next_pos = match self.files.range((pos + 1)..).next() {
-                Some((pos, _)) => *pos,
-                None => self.last_position,
-            };
+            let to_read = core::cmp::min(remaining as u64, available) as usize;
+
+            self.files[index]
+                .read(offset, &mut buf[bytes_read..(bytes_read + to_read)])
+                .await?;
+            bytes_read += to_read;
+        }
+
+        Ok(())
+    }
+}
 
-            let mut handle_iter = self.files.range_mut(..=pos);
-            // As long as the B-tree is not empty, this should never fail
-            let handle = handle_iter.next_back().unwrap();
+/// Why the parts given to a `SplitFileReader` do not form one image
+#[derive(Debug)]
+pub enum SplitValidationError<E> {
+    /// The part with this zero-based index is empty
+    EmptyPart(u64),
+    /// The part is larger than the split size, or has a different size
+    /// than the others and does not end on a block boundary
+    PartSize {
+        index: u64,
+        expected: u64,
+        found: u64,
+    },
+    /// The parts add up to a different size than the index table describes
+    TotalSize { expected: u64, found: u64 },
+    /// The header or index table could not be read
+    Image(crate::layout::Error<E>),
+}
 
-            assert!(next_pos > *handle.0);
-            let handle_size = next_pos - handle.0;
+impl<E: Display> Display for SplitValidationError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyPart(index) => write!(f, "Part {} is empty", index + 1),
+            Self::PartSize {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Part {} is {} bytes but should be {} bytes; it may belong to a different image",
+                index + 1,
+                found,
+                expected
+            ),
+            Self::TotalSize { expected, found } => write!(
+                f,
+                "Parts add up to {} bytes but the index table ends at {} bytes; parts may be \
+                 missing or belong to a different image",
+                found, expected
+            ),
+            Self::Image(e) => e.fmt(f),
+        }
+    }
+}
 
-            let handle_offset = pos - handle.0;
-            let handle: &mut R = handle.1;
+impl<E: Display + Debug> std::error::Error for SplitValidationError<E> {}
 
-            assert!(handle_size > handle_offset);
-            let bytes_to_split = handle_size - handle_offset;
+impl<E: Send + Sync + From<OutOfBounds>, R: crate::read::Read<ReadError = E>>
+    SplitFileReader<E, R>
+{
+    /// Check that the parts form a single compressed image, using its header
+    /// and index table as ground truth
+    ///
+    /// No part may be empty, and together they must end where the final
+    /// index entry points, or just after the checksum trailer. Every part
+    /// but the last must be exactly `split_size` bytes, or the size of the
+    /// first part if it is not given, unless every part ends on a block
+    /// boundary as in block-aligned images, which may not exceed `split_size`.
+    /// Only the total is checked for legacy images.
+    #[maybe_async]
+    pub async fn validate(
+        &mut self,
+        split_size: Option<u64>,
+    ) -> Result<(), SplitValidationError<E>> {
+        use crate::read::Read;
 
-            let to_read = core::cmp::min(buf.len() - bytes_read, bytes_to_split as usize);
-            assert_ne!(to_read, 0);
+        if let Some(index) = self.part_sizes.iter().position(|size| *size == 0) {
+            return Err(SplitValidationError::EmptyPart(index as u64));
+        }
 
-            handle
-                .read(pos, &mut buf[bytes_read..(bytes_read + to_read)])
-                .await?;
-            bytes_read += to_read;
+        let mut header = [0; 24];
+        self.read(0, &mut header)
+            .await
+            .map_err(|e| SplitValidationError::Image(crate::layout::Error::Other(e)))?;
+        let header =
+            crate::layout::CSOHeader::deserialize(&header).map_err(SplitValidationError::Image)?;
+
+        let mut index_table = vec![0; header.index_table_len() * 4];
+        self.read(24, &mut index_table)
+            .await
+            .map_err(|e| SplitValidationError::Image(crate::layout::Error::Other(e)))?;
+        let index_table = crate::index::IndexTable::deserialize(index_table);
+        let entry_pos = |idx: usize| {
+            let pos: u32 = index_table[idx].position().into();
+            (pos as u64) << header.alignment
+        };
+
+        let end = entry_pos(index_table.len() - 1);
+        let trailer_size =
+            crate::layout::ChecksumTrailer::HEADER_SIZE as u64 + 4 * (index_table.len() as u64 - 1);
+        let total = self
+            .size()
+            .await
+            .map_err(|e| SplitValidationError::Image(crate::layout::Error::Other(e)))?;
+        // Images from earlier versions rounded the final index entry down,
+        // so their last block may run up to an alignment unit past it
+        let align_b = 1u64 << header.alignment;
+        let matches_end = |end: u64| total >= end && total - end < align_b;
+        if !matches_end(end) && !matches_end(end + trailer_size) {
+            return Err(SplitValidationError::TotalSize {
+                expected: end,
+                found: total,
+            });
+        }
+
+        if self.legacy {
+            return Ok(());
+        }
+
+        if let Some(split_size) = split_size {
+            if let Some(index) = self.part_sizes.iter().position(|size| *size > split_size) {
+                return Err(SplitValidationError::PartSize {
+                    index: index as u64,
+                    expected: split_size,
+                    found: self.part_sizes[index],
+                });
+            }
+        }
+
+        let before_last = &self.part_sizes[..self.part_sizes.len() - 1];
+        let expected = split_size.unwrap_or(self.part_sizes[0]);
+        if before_last.iter().all(|size| *size == expected) {
+            return Ok(());
+        }
+
+        // Otherwise every part must end where a block starts, as
+        // block-aligned parts do
+        let block_starts: Vec<u64> = (0..index_table.len()).map(entry_pos).collect();
+        let mut start = 0;
+        for (index, size) in before_last.iter().enumerate() {
+            start += size;
+            if block_starts.binary_search(&start).is_err() {
+                return Err(SplitValidationError::PartSize {
+                    index: index as u64,
+                    expected,
+                    found: *size,
+                });
+            }
         }
 
         Ok(())
     }
 }
+
+#[maybe_async]
+impl<E: Send + Sync + From<OutOfBounds>, R: crate::read::Read<ReadError = E>>
+    crate::write::SectorReader for SplitFileReader<E, R>
+{
+    type ReadError = E;
+
+    async fn size(&mut self) -> Result<u64, E> {
+        crate::read::Read::size(self).await
+    }
+
+    async fn read_sector(&mut self, sector: usize, sector_size: u32) -> Result<Vec<u8>, E> {
+        let pos = (sector as u64) * (sector_size as u64);
+        let mut buf = vec![0; sector_size as usize];
+        crate::read::Read::read(self, pos, &mut buf).await?;
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::mem::{MemError, MemFile, MemFilesystem};
+
+    /// An image of text, zero and noise blocks, so that compressed blocks
+    /// vary in size and some are stored raw
+    pub(crate) fn test_image(sectors: usize) -> Vec<u8> {
+        const WORDS: [&[u8]; 8] = [
+            b"sector ", b"index ", b"block ", b"part ", b"image ", b"split ", b"the ", b"of ",
+        ];
+
+        let mut state: u32 = 0x1234_5678;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        let mut image = Vec::with_capacity(sectors * 2048);
+        for sector in 0..sectors {
+            let end = (sector + 1) * 2048;
+            match sector % 7 {
+                3 => image.resize(end, 0),
+                5 => {
+                    while image.len() < end {
+                        image.push(next() as u8);
+                    }
+                }
+                _ => {
+                    while image.len() < end {
+                        image.extend_from_slice(WORDS[next() as usize % WORDS.len()]);
+                    }
+                    image.truncate(end);
+                }
+            }
+        }
+        image
+    }
+
+    #[maybe_async]
+    pub(crate) async fn compress(
+        image: &[u8],
+        split_size: SplitSize,
+        block_aligned: bool,
+    ) -> MemFilesystem {
+        let fs = MemFilesystem::new();
+        let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.iso"));
+        output.set_split_size(split_size);
+        output.set_block_aligned(block_aligned);
+
+        let mut input = MemFile::new(image.to_vec());
+        crate::write::write_ciso_image(&mut input, &mut output, |_| {})
+            .await
+            .unwrap();
+        output.close().await.unwrap();
+        fs
+    }
+
+    #[maybe_async]
+    pub(crate) async fn decompress(fs: &MemFilesystem, split_size: SplitSize) -> Vec<u8> {
+        let mut reader = open_split(&mut fs.clone(), Path::new("game.cso"))
+            .await
+            .unwrap();
+        reader.validate(Some(split_size.bytes())).await.unwrap();
+
+        let mut reader = crate::read::CSOReader::new(reader).await.unwrap();
+        let mut image = vec![0; reader.file_size() as usize];
+        reader.read_offset(0, &mut image).await.unwrap();
+        image
+    }
+
+    /// An image and its compressed form laid out as earlier versions wrote
+    /// it, with the final index entry rounded down past an unpadded last
+    /// block
+    #[maybe_async]
+    pub(crate) async fn unpadded_image() -> (Vec<u8>, Vec<u8>) {
+        // The last block is text rather than zeros, so a cut-off end shows
+        for sectors in (64..).step_by(7) {
+            let image = test_image(sectors);
+            let fs = compress(&image, SplitSize::None, false).await;
+            let mut cso = fs.file("game.cso").unwrap().contents();
+            let starts = block_starts(&fs);
+            let header =
+                crate::layout::CSOHeader::deserialize::<()>(cso[..24].try_into().unwrap()).unwrap();
+
+            let last = starts[sectors - 1];
+            let index_table =
+                crate::index::IndexTable::deserialize(cso[24..][..(sectors + 1) * 4].to_vec());
+            let lz4 = index_table[sectors - 1].compression_type();
+            let stored = &cso[last as usize..starts[sectors] as usize];
+            let len = crate::read::encoded_len(2048, lz4, stored).unwrap();
+            if len.is_multiple_of(1 << header.alignment) {
+                continue;
+            }
+
+            let end = last + len as u64;
+            cso.truncate(end as usize);
+            let entry = crate::layout::IndexTableEntry::default()
+                .with_position(arbitrary_int::u31::new((end >> header.alignment) as u32));
+            crate::util::serialize_u32_le(entry.raw_value(), &mut cso[(24 + 4 * sectors)..][..4]);
+            return (image, cso);
+        }
+        unreachable!()
+    }
+
+    /// Sizes of each part, in part order
+    fn part_sizes(fs: &MemFilesystem) -> Vec<u64> {
+        let parts = fs.paths().len();
+        if parts == 1 {
+            return vec![fs.file("game.cso").unwrap().len()];
+        }
+        (1..=parts)
+            .map(|index| fs.file(format!("game.{}.cso", index)).unwrap().len())
+            .collect()
+    }
+
+    /// Block start positions of an unsplit image
+    fn block_starts(fs: &MemFilesystem) -> Vec<u64> {
+        let cso = fs.file("game.cso").unwrap().contents();
+        let header =
+            crate::layout::CSOHeader::deserialize::<()>(cso[..24].try_into().unwrap()).unwrap();
+        let index_table = crate::index::IndexTable::deserialize(
+            cso[24..][..header.index_table_len() * 4].to_vec(),
+        );
+        (0..index_table.len())
+            .map(|idx| {
+                let pos: u32 = index_table[idx].position().into();
+                (pos as u64) << header.alignment
+            })
+            .collect()
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn round_trip_single_part() {
+        let image = test_image(64);
+        let fs = compress(&image, SplitSize::None, false).await;
+
+        assert_eq!(fs.paths(), vec![PathBuf::from("game.cso")]);
+        let decompressed = decompress(&fs, SplitSize::None).await;
+        assert_eq!(decompressed, image);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn round_trip_exact_parts() {
+        // Find an image whose compressed size divides evenly into parts, so
+        // the last part is as full as the others
+        for sectors in 48..128 {
+            let image = test_image(sectors);
+            let unsplit = compress(&image, SplitSize::None, false).await;
+            let total = unsplit.file("game.cso").unwrap().len();
+            let Some(parts) = (3..=8).find(|parts| total.is_multiple_of(*parts)) else {
+                continue;
+            };
+
+            let split_size = SplitSize::Bytes(total / parts);
+            let fs = compress(&image, split_size, false).await;
+            assert_eq!(part_sizes(&fs), vec![total / parts; parts as usize]);
+            assert!(fs.file(format!("game.{}.cso", parts)).is_some());
+            let decompressed = decompress(&fs, split_size).await;
+            assert_eq!(decompressed, image);
+            return;
+        }
+
+        panic!("No test image divides evenly into parts");
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn round_trip_straddling_block() {
+        let image = test_image(64);
+        let unsplit = compress(&image, SplitSize::None, false).await;
+        let starts = block_starts(&unsplit);
+
+        // Split in the middle of the longest block, which is stored raw
+        let (start, end) = starts
+            .windows(2)
+            .map(|w| (w[0], w[1]))
+            .max_by_key(|(start, end)| end - start)
+            .unwrap();
+        let split_size = (start + end) / 2;
+        assert!(!start.is_multiple_of(split_size));
+
+        let split = SplitSize::Bytes(split_size);
+        let fs = compress(&image, split, false).await;
+        let sizes = part_sizes(&fs);
+        assert!(sizes.len() > 1);
+        assert!(sizes[..sizes.len() - 1]
+            .iter()
+            .all(|size| *size == split_size));
+        let decompressed = decompress(&fs, split).await;
+        assert_eq!(decompressed, image);
+
+        // Block-aligned parts end before the block instead
+        let fs = compress(&image, split, true).await;
+        assert_eq!(part_sizes(&fs)[0], start);
+        let decompressed = decompress(&fs, split).await;
+        assert_eq!(decompressed, image);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn find_parts_of_names_like_parts() {
+        let mut fs = MemFilesystem::new();
+        for name in [
+            "Halo_2.iso",
+            "PGR_2.cso",
+            "PGR_3.cso",
+            "game.1.cso",
+            "game.2.cso",
+            "lone.1.cso",
+            "gap.1.cso",
+            "gap.3.cso",
+        ] {
+            fs.insert(name, Vec::new());
+        }
+
+        let parts = find_parts(&mut fs, Path::new("Halo_2.iso")).await.unwrap();
+        assert_eq!(parts, vec![PathBuf::from("./Halo_2.iso")]);
+        let parts = find_parts(&mut fs, Path::new("PGR_2.cso")).await.unwrap();
+        assert_eq!(parts, vec![PathBuf::from("./PGR_2.cso")]);
+        let parts = find_parts(&mut fs, Path::new("lone.1.cso")).await.unwrap();
+        assert_eq!(parts, vec![PathBuf::from("./lone.1.cso")]);
+
+        let parts = find_parts(&mut fs, Path::new("game.2.cso")).await.unwrap();
+        assert_eq!(
+            parts,
+            vec![PathBuf::from("./game.1.cso"), PathBuf::from("./game.2.cso")]
+        );
+        let result = find_parts(&mut fs, Path::new("gap.3.cso")).await;
+        assert!(matches!(result, Err(SplitOpenError::MissingPart(1))));
+
+        let entries = fs.paths();
+        let entries: Vec<&str> = entries.iter().filter_map(|p| p.to_str()).collect();
+        assert_eq!(split_image_name(&entries, "Halo_2.iso"), None);
+        assert_eq!(split_image_name(&entries, "PGR_3.cso"), None);
+        assert_eq!(
+            split_image_name(&entries, "game.2.cso").as_deref(),
+            Some("game.cso")
+        );
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn resume_custom_naming() {
+        use crate::write::{self, CancellationToken, WriteOptions};
+
+        let image = test_image(64);
+        let split_size = SplitSize::Bytes(16 * 1024);
+        let disc = String::from("disc");
+        let naming = move || {
+            let disc = disc.clone();
+            SplitNaming::Custom(Box::new(move |stem, extension, index| {
+                let mut name = stem.to_os_string();
+                name.push(format!("-{}{}.{}", disc, index, extension));
+                name
+            }))
+        };
+
+        let fs = MemFilesystem::new();
+        let token = CancellationToken::new();
+        let options = WriteOptions {
+            cancellation: Some(token.clone()),
+            checkpoint_interval: Some(8),
+            ..WriteOptions::default()
+        };
+        let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.iso"));
+        output.set_split_size(split_size);
+        output.set_naming(naming());
+        let result = write::write_ciso_image_with_options(
+            &mut MemFile::new(image.clone()),
+            &mut output,
+            &options,
+            |info| {
+                if let ProgressInfo::SectorFinished(block) = info {
+                    if block.sector == 36 {
+                        token.cancel();
+                    }
+                }
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        output.close().await.unwrap();
+        assert!(fs.file("game-disc0.cso").is_some());
+
+        // The parts can only be found when the naming is known
+        let result = open_split(&mut fs.clone(), Path::new("game.cso")).await;
+        assert!(matches!(result, Err(SplitOpenError::NotFound)));
+
+        let mut existing = open_split_named(&mut fs.clone(), Path::new("game.cso"), &naming())
+            .await
+            .unwrap();
+        let mut output = SplitOutput::reopen(fs.clone(), PathBuf::from("game.iso"));
+        output.set_split_size(split_size);
+        output.set_naming(naming());
+        output.set_existing_parts(&existing.part_sizes());
+        write::resume_ciso_image(
+            &mut MemFile::new(image.clone()),
+            &mut existing,
+            &mut output,
+            &WriteOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        output.close().await.unwrap();
+
+        let expected = compress(&image, split_size, false).await;
+        assert_eq!(fs.paths().len(), expected.paths().len());
+
+        let mut reader = open_split_named(&mut fs.clone(), Path::new("game.cso"), &naming())
+            .await
+            .unwrap();
+        reader.validate(Some(split_size.bytes())).await.unwrap();
+        let mut reader = crate::read::CSOReader::new(reader).await.unwrap();
+        let mut decompressed = vec![0; reader.file_size() as usize];
+        reader.read_offset(0, &mut decompressed).await.unwrap();
+        assert_eq!(decompressed, image);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn read_unpadded_last_block() {
+        let (image, cso) = unpadded_image().await;
+        let fs = MemFilesystem::new();
+        fs.insert("game.cso", cso);
+
+        let mut reader = open_split(&mut fs.clone(), Path::new("game.cso"))
+            .await
+            .unwrap();
+        reader.validate(None).await.unwrap();
+        let mut reader = crate::read::CSOReader::new(reader).await.unwrap();
+        let mut decompressed = vec![0; reader.file_size() as usize];
+        reader.read_offset(0, &mut decompressed).await.unwrap();
+        assert_eq!(decompressed, image);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn existing_parts_are_kept() {
+        let image = test_image(64);
+        let mut fs = MemFilesystem::new();
+        fs.insert("game.1.iso", vec![1; 100]);
+        fs.insert("game.2.iso", vec![2; 100]);
+
+        // An unsplit output goes straight to the plain name
+        let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.cso"));
+        output.set_extension("iso");
+        output.set_split_size(SplitSize::None);
+        output.atomic_write(0, &image).await.unwrap();
+        output.close().await.unwrap();
+        assert_eq!(fs.file("game.iso").unwrap().contents(), image);
+
+        // A split output refuses to replace parts it did not create
+        fs.remove(Path::new("game.iso")).await.unwrap();
+        let mut output = SplitOutput::new(fs.clone(), PathBuf::from("game.cso"));
+        output.set_extension("iso");
+        output.set_split_size(SplitSize::Bytes(64 * 1024));
+        let result = output.atomic_write(0, &image).await;
+        assert!(matches!(result, Err(MemError::AlreadyExists(_))));
+        output.rollback().await.unwrap();
+
+        assert_eq!(
+            fs.paths(),
+            vec![PathBuf::from("game.1.iso"), PathBuf::from("game.2.iso")]
+        );
+        assert_eq!(fs.file("game.1.iso").unwrap().contents(), vec![1; 100]);
+        assert_eq!(fs.file("game.2.iso").unwrap().contents(), vec![2; 100]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{Debug, Display};
+
+use maybe_async::maybe_async;
+
+use crate::{layout, read, write::SectorReader};
+
+#[derive(Debug)]
+pub enum VerifyError<OriginalError, ImageError> {
+    OriginalError(OriginalError),
+    ImageError(layout::Error<ImageError>),
+}
+
+impl<OE: Display, IE: Display> Display for VerifyError<OE, IE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OriginalError(e) => e.fmt(f),
+            Self::ImageError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<OE: Display + Debug, IE: Display + Debug> std::error::Error for VerifyError<OE, IE> {}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Also check that every block's stored length agrees with its index entry
+    pub check_stored_lengths: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Number of sectors compared
+    pub sectors: usize,
+    /// Size of the original image, if it differs from the compressed image
+    pub size_mismatch: Option<u64>,
+    pub first_mismatch: Option<usize>,
+    /// Sectors whose contents differ or could not be decoded
+    pub mismatched_sectors: usize,
+    /// Sectors whose stored length does not match their index entry
+    pub bad_stored_lengths: Vec<usize>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.size_mismatch.is_none()
+            && self.mismatched_sectors == 0
+            && self.bad_stored_lengths.is_empty()
+    }
+}
+
+/// Compare a compressed image block by block against the original it was
+/// created from
+#[maybe_async]
+pub async fn verify_image<I, E, R>(
+    original: &mut I,
+    image: &mut read::CSOReader<E, R>,
+    options: &VerifyOptions,
+    mut progress_callback: impl FnMut(usize),
+) -> Result<VerifyReport, VerifyError<I::ReadError, E>>
+where
+    I: SectorReader,
+    R: read::Read<ReadError = E>,
+{
+    let mut report = VerifyReport::default();
+
+    let original_size = original.size().await.map_err(VerifyError::OriginalError)?;
+    if original_size != image.file_size() {
+        report.size_mismatch = Some(original_size);
+    }
+
+    let block_size = image.header().block_size;
+    let sectors = core::cmp::min(
+        image.sector_count() as u64,
+        original_size / block_size as u64,
+    ) as usize;
+
+    for sector in 0..sectors {
+        let expected = original
+            .read_sector(sector, block_size)
+            .await
+            .map_err(VerifyError::OriginalError)?;
+
+        let matches = match image.read_sector(sector as u64).await {
+            Ok(data) => data == expected,
+            Err(layout::Error::CorruptBlock(_)) => false,
+            Err(e) => return Err(VerifyError::ImageError(e)),
+        };
+
+        if !matches {
+            report.first_mismatch.get_or_insert(sector);
+            report.mismatched_sectors += 1;
+        }
+
+        if options.check_stored_lengths
+            && !image
+                .stored_length_matches(sector as u64)
+                .await
+                .map_err(VerifyError::ImageError)?
+        {
+            report.bad_stored_lengths.push(sector);
+        }
+
+        report.sectors += 1;
+        progress_callback(sector);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::split::tests::{compress, test_image};
+    use crate::split::SplitSize;
+
+    #[maybe_async]
+    async fn verify(original: Vec<u8>, cso: Vec<u8>, check_stored_lengths: bool) -> VerifyReport {
+        let mut image = read::CSOReader::new(MemFile::new(cso)).await.unwrap();
+        let options = VerifyOptions {
+            check_stored_lengths,
+        };
+        verify_image(&mut MemFile::new(original), &mut image, &options, |_| {})
+            .await
+            .unwrap()
+    }
+
+    #[maybe_async]
+    async fn compressed(image: &[u8]) -> Vec<u8> {
+        let fs = compress(image, SplitSize::None, false).await;
+        fs.file("game.cso").unwrap().contents()
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn mismatched_sectors() {
+        let image = test_image(64);
+        let cso = compressed(&image).await;
+
+        let report = verify(image.clone(), cso.clone(), true).await;
+        assert!(report.is_ok());
+        assert_eq!(report.sectors, 64);
+
+        let mut original = image.clone();
+        original[7 * 2048 + 100] ^= 1;
+        original[9 * 2048] ^= 1;
+        let report = verify(original, cso.clone(), false).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.first_mismatch, Some(7));
+        assert_eq!(report.mismatched_sectors, 2);
+
+        let mut original = image;
+        original.truncate(60 * 2048);
+        let report = verify(original, cso, false).await;
+        assert_eq!(report.size_mismatch, Some(60 * 2048));
+        assert_eq!(report.sectors, 60);
+        assert_eq!(report.mismatched_sectors, 0);
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn stored_length_against_index() {
+        let image = test_image(64);
+        let mut cso = compressed(&image).await;
+
+        // Point the final entry one alignment unit past the end of the last
+        // block, which still decodes
+        let entry = 24 + 64 * 4;
+        let end = crate::util::deserialize_u32_le(&cso[entry..][..4]);
+        crate::util::serialize_u32_le(end + 1, &mut cso[entry..][..4]);
+        cso.extend_from_slice(&[0; 4]);
+
+        let report = verify(image.clone(), cso.clone(), false).await;
+        assert!(report.is_ok());
+
+        let report = verify(image, cso, true).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.mismatched_sectors, 0);
+        assert_eq!(report.bad_stored_lengths, vec![63]);
+    }
+}
@@ -13,6 +13,10 @@ pub enum CSOCreationError<ReadError, WriteError> {
     CompressionError(std::io::Error),
     ReadError(ReadError),
     WriteError(WriteError),
+    /// The image being resumed was created from a different input
+    ResumeMismatch,
+    /// Compression was stopped through a `CancellationToken`
+    Cancelled,
 }
 
 impl<RE: Display, WE: Display> Display for CSOCreationError<RE, WE> {
@@ -22,103 +26,482 @@ impl<RE: Display, WE: Display> Display for CSOCreationError<RE, WE> {
             Self::CompressionError(e) => Display::fmt(e, f),
             Self::ReadError(e) => e.fmt(f),
             Self::WriteError(e) => e.fmt(f),
+            Self::ResumeMismatch => write!(f, "Existing image does not match the input"),
+            Self::Cancelled => write!(f, "CSO creation was cancelled"),
         }
     }
 }
 
 impl<RE: Display + Debug, WE: Display + Debug> std::error::Error for CSOCreationError<RE, WE> {}
 
+/// Running totals reported after each block is written
+#[derive(Clone, Copy, Debug)]
+pub struct BlockProgress {
+    pub sector: usize,
+    /// Uncompressed bytes consumed so far
+    pub input_bytes: u64,
+    /// Bytes written so far, including the header and index table
+    pub output_bytes: u64,
+    /// Whether this block was stored without compression
+    pub stored_raw: bool,
+}
+
+impl BlockProgress {
+    /// Current compression ratio (output size over input size)
+    pub fn ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 1.0;
+        }
+
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+}
+
 #[non_exhaustive]
 pub enum ProgressInfo {
     SectorCount(usize),
-    SectorFinished,
+    /// Compression is resuming at the given sector, with all earlier blocks
+    /// already present in the output
+    Resumed(usize),
+    SectorFinished(BlockProgress),
+    /// The partial index table was written, so an interrupted compression
+    /// can be resumed with every block before the given sector kept
+    Checkpoint(usize),
+    /// Blocks that skipped the compressor, sent once all blocks are written
+    FastPathBlocks {
+        /// All-zero blocks stored with a precomputed encoding
+        zero: usize,
+        /// High-entropy blocks stored raw without trying to compress them
+        incompressible: usize,
+    },
+    /// A new output part was created by a split writer, at the path `name`
+    SplitPartCreated {
+        index: u64,
+        name: std::ffi::OsString,
+    },
     Finished,
 }
 
+/// Compression method that can be used for an individual block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockCodec {
+    /// LZ4 block, flagged by bit 31 of the index entry
+    Lz4,
+    /// Raw deflate stream at the given level (0-9)
+    ///
+    /// Deflate blocks leave bit 31 clear and are told apart from raw blocks
+    /// by being shorter than the block size, as in CSO v2. Loaders that only
+    /// understand LZ4 cannot read images containing deflate blocks.
+    Deflate(u32),
+}
+
+/// Handle used to stop a running compression, possibly from another thread
+///
+/// The token is checked before each block. When compression is cancelled the
+/// output holds the header and every block completed so far, but no index
+/// table. Split outputs keep the parts created up to that point; they still
+/// need to be closed by the caller.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Options controlling how an image is compressed
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    /// Codecs tried on every block. The smallest result is stored, or the
+    /// block is stored raw if none of them save enough space.
+    pub codecs: Vec<BlockCodec>,
+
+    /// Token checked between blocks to stop compression early
+    pub cancellation: Option<CancellationToken>,
+
+    /// Number of blocks between writes of the partial index table, which
+    /// allow an interrupted compression to be resumed with `resume_ciso_image`
+    pub checkpoint_interval: Option<usize>,
+
+    /// Hash the uncompressed data while compressing it, returning the
+    /// digests in the `WriteReport`
+    pub compute_hashes: bool,
+
+    /// Append a table of per-block CRC32s after the last block, which
+    /// `CSOReader` can use to detect corrupted blocks
+    pub block_checksums: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            codecs: vec![BlockCodec::Lz4],
+            cancellation: None,
+            checkpoint_interval: Some(4096),
+            compute_hashes: false,
+            block_checksums: false,
+        }
+    }
+}
+
+/// Summary of a completed compression
+#[derive(Clone, Debug)]
+pub struct WriteReport {
+    /// Header of the image that was written
+    pub header: layout::CSOHeader,
+    /// Size of the compressed image, including any checksum trailer
+    pub compressed_size: u64,
+    /// Digests of the uncompressed data, if `WriteOptions::compute_hashes` was set
+    pub digests: Option<crate::hash::Digests>,
+}
+
+fn compress_block<RE, WE>(
+    codec: BlockCodec,
+    data: &[u8],
+) -> Result<Vec<u8>, CSOCreationError<RE, WE>> {
+    match codec {
+        BlockCodec::Lz4 => {
+            let cfg = lz4_flex::frame::FrameInfo::new()
+                .block_mode(lz4_flex::frame::BlockMode::Independent)
+                .block_size(lz4_flex::frame::BlockSize::Max64KB)
+                .content_checksum(false)
+                .block_checksums(false)
+                .legacy_frame(true)
+                .content_size(None);
+
+            let mut data_compressed =
+                lz4_flex::frame::FrameEncoder::with_frame_info(cfg, Vec::new());
+            data_compressed
+                .write_all(data)
+                .map_err(CSOCreationError::CompressionError)?;
+            let data_compressed = data_compressed
+                .finish()
+                .map_err(CSOCreationError::LZ4Error)?;
+
+            // Strip header and footer
+            Ok(data_compressed[7..(data_compressed.len() - 4)].to_vec())
+        }
+        BlockCodec::Deflate(level) => {
+            let mut encoder = flate2::write::DeflateEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            );
+            encoder
+                .write_all(data)
+                .map_err(CSOCreationError::CompressionError)?;
+            encoder.finish().map_err(CSOCreationError::CompressionError)
+        }
+    }
+}
+
+/// Byte entropy (in bits per byte) above which a block is stored raw without
+/// trying to compress it. Uniformly random 2048 byte blocks measure about 7.91.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.88;
+
+fn is_incompressible(data: &[u8]) -> bool {
+    let mut histogram = [0usize; 256];
+    for b in data {
+        histogram[*b as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    let entropy: f64 = histogram
+        .iter()
+        .filter(|c| **c != 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    entropy > INCOMPRESSIBLE_ENTROPY
+}
+
+/// A compressed block and the codec that produced it
+type EncodedBlock = (BlockCodec, Vec<u8>);
+
+/// Compress a block with every configured codec and return the smallest
+/// result, or `None` if the block should be stored raw.
+fn encode_block<RE, WE>(
+    options: &WriteOptions,
+    data: &[u8],
+    align_b: usize,
+) -> Result<Option<EncodedBlock>, CSOCreationError<RE, WE>> {
+    let mut best: Option<EncodedBlock> = None;
+    for codec in options.codecs.iter() {
+        let data_compressed = compress_block(*codec, data)?;
+        if best
+            .as_ref()
+            .is_none_or(|(_, b)| data_compressed.len() < b.len())
+        {
+            best = Some((*codec, data_compressed));
+        }
+    }
+
+    // Deflate blocks are only recognized by being shorter than a block,
+    // so they must stay short even once alignment padding is added.
+    Ok(best.filter(|(codec, data_compressed)| {
+        let compressed_len = data_compressed.len();
+        match codec {
+            BlockCodec::Lz4 => compressed_len + 12 < data.len(),
+            BlockCodec::Deflate(_) => compressed_len + core::cmp::max(12, align_b) < data.len(),
+        }
+    }))
+}
+
+/// Pad the output with zeros up to the next multiple of `align_b`, returning
+/// the new position
+#[maybe_async]
+pub(crate) async fn write_padding<O: AsyncWriter>(
+    output: &mut O,
+    position: u64,
+    align_b: u64,
+) -> Result<u64, O::WriteError> {
+    let align = position & (align_b - 1);
+    if align == 0 {
+        return Ok(position);
+    }
+
+    let align = align_b - align;
+    let align_bytes = vec![0; align as usize];
+    output.atomic_write(position, &align_bytes).await?;
+    Ok(position + align)
+}
+
 #[maybe_async]
 async fn write_ciso_data<I: SectorReader, O: AsyncWriter>(
     input: &mut I,
     output: &mut O,
     header: &layout::CSOHeader,
     index_table: &mut index::IndexTable,
+    start_sector: usize,
+    options: &WriteOptions,
     mut progress_callback: impl FnMut(ProgressInfo),
-) -> Result<(), CSOCreationError<I::ReadError, O::WriteError>> {
-    let mut position: u64 = 24 + 4 * index_table.len() as u64;
-    let cfg = lz4_flex::frame::FrameInfo::new()
-        .block_mode(lz4_flex::frame::BlockMode::Independent)
-        .block_size(lz4_flex::frame::BlockSize::Max64KB)
-        .content_checksum(false)
-        .block_checksums(false)
-        .legacy_frame(true)
-        .content_size(None);
+) -> Result<WriteReport, CSOCreationError<I::ReadError, O::WriteError>> {
+    let mut hasher = options.compute_hashes.then(crate::hash::Hasher::new);
+    let mut checksums = options.block_checksums.then(Vec::new);
+    let mut zero_blocks = 0;
+    let mut incompressible_blocks = 0;
+
+    // Blocks that are already written when resuming still need hashing, and
+    // counting so that the fast path totals cover the whole image
+    for sector in 0..start_sector {
+        let data = input
+            .read_sector(sector, header.block_size)
+            .await
+            .map_err(CSOCreationError::ReadError)?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&data);
+        }
+        if let Some(checksums) = checksums.as_mut() {
+            checksums.push(crc32fast::hash(&data));
+        }
+
+        if data.iter().all(|b| *b == 0) {
+            zero_blocks += 1;
+        } else if is_incompressible(&data) {
+            incompressible_blocks += 1;
+        }
+    }
+
+    let mut position: u64 = if start_sector == 0 {
+        24 + 4 * index_table.len() as u64
+    } else {
+        let start: u32 = index_table[start_sector].position().into();
+        (start as u64) << header.alignment
+    };
+    let mut checkpoint_start = start_sector;
 
     let align_b = 1 << header.alignment;
     let align_m = align_b - 1;
 
-    for sector in 0..(index_table.len() - 1) {
-        let align = position & (align_m as u64);
-        if align != 0 {
-            let align = (align_b as u64) - align;
-            let align_bytes = vec![0; align as usize];
-            output
-                .atomic_write(position, &align_bytes)
-                .await
-                .map_err(CSOCreationError::WriteError)?;
-            position += align;
+    let zero_block = encode_block(options, &vec![0; header.block_size as usize], align_b)?;
+
+    for sector in start_sector..(index_table.len() - 1) {
+        if options
+            .cancellation
+            .as_ref()
+            .is_some_and(|c| c.is_cancelled())
+        {
+            return Err(CSOCreationError::Cancelled);
         }
 
+        position = write_padding(output, position, align_b as u64)
+            .await
+            .map_err(CSOCreationError::WriteError)?;
+
         let data = input
             .read_sector(sector, header.block_size)
             .await
             .map_err(CSOCreationError::ReadError)?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&data);
+        }
+        if let Some(checksums) = checksums.as_mut() {
+            checksums.push(crc32fast::hash(&data));
+        }
 
-        let mut data_compressed =
-            lz4_flex::frame::FrameEncoder::with_frame_info(cfg.clone(), Vec::new());
-        data_compressed
-            .write_all(&data)
-            .map_err(CSOCreationError::CompressionError)?;
-        let data_compressed = data_compressed
-            .finish()
-            .map_err(CSOCreationError::LZ4Error)?;
-
-        // Strip header and footer
-        let data_compressed = &data_compressed[7..(data_compressed.len() - 4)];
-
-        let compressed_len = data_compressed.len();
-        let is_compressed = compressed_len + 12 < data.len();
+        let best = if data.iter().all(|b| *b == 0) {
+            zero_blocks += 1;
+            zero_block.clone()
+        } else if is_incompressible(&data) {
+            incompressible_blocks += 1;
+            None
+        } else {
+            encode_block(options, &data, align_b)?
+        };
 
         index_table[sector] = layout::IndexTableEntry::default()
             .with_position(u31::new((position >> header.alignment) as u32))
-            .with_compression_type(is_compressed);
+            .with_compression_type(matches!(best, Some((BlockCodec::Lz4, _))));
 
-        let data = if is_compressed {
-            data_compressed
-        } else {
-            &data
+        let stored_raw = best.is_none();
+        let data = match &best {
+            Some((_, data_compressed)) => data_compressed,
+            None => &data,
         };
+        let padded_len = (data.len() as u64 + align_m as u64) & !(align_m as u64);
+        output.keep_together(position, padded_len);
         output
             .atomic_write(position, data)
             .await
             .map_err(CSOCreationError::WriteError)?;
         position += data.len() as u64;
 
-        progress_callback(ProgressInfo::SectorFinished);
+        if options
+            .checkpoint_interval
+            .is_some_and(|interval| (sector + 1) % interval == 0)
+        {
+            // Record where the next block will start, so the last block
+            // written can be located when resuming.
+            let next_position = (position + align_m as u64) & !(align_m as u64);
+            index_table[sector + 1] = layout::IndexTableEntry::default()
+                .with_position(u31::new((next_position >> header.alignment) as u32));
+
+            let checkpoint = index_table.serialize_range(checkpoint_start..(sector + 2));
+            output
+                .atomic_write(24 + 4 * checkpoint_start as u64, &checkpoint)
+                .await
+                .map_err(CSOCreationError::WriteError)?;
+            checkpoint_start = sector + 1;
+            progress_callback(ProgressInfo::Checkpoint(sector + 1));
+        }
+
+        let input_bytes = (sector as u64 + 1) * header.block_size as u64;
+        progress_callback(ProgressInfo::SectorFinished(BlockProgress {
+            sector,
+            input_bytes: core::cmp::min(input_bytes, header.uncompressed_size),
+            output_bytes: position,
+            stored_raw,
+        }));
     }
 
+    // The final entry marks the end of the last block, which is only exact
+    // once the data is padded to the alignment.
+    position = write_padding(output, position, align_b as u64)
+        .await
+        .map_err(CSOCreationError::WriteError)?;
+
     let index_table_len = index_table.len();
     index_table[index_table_len - 1] = layout::IndexTableEntry::default()
         .with_position(u31::new((position >> header.alignment) as u32));
-    progress_callback(ProgressInfo::SectorFinished);
+    progress_callback(ProgressInfo::SectorFinished(BlockProgress {
+        sector: index_table_len - 1,
+        input_bytes: header.uncompressed_size,
+        output_bytes: position,
+        stored_raw: false,
+    }));
+    progress_callback(ProgressInfo::FastPathBlocks {
+        zero: zero_blocks,
+        incompressible: incompressible_blocks,
+    });
 
-    Ok(())
+    let mut compressed_size = position;
+    if let Some(checksums) = checksums {
+        let trailer = layout::ChecksumTrailer { checksums }.serialize();
+        output
+            .atomic_write(position, &trailer)
+            .await
+            .map_err(CSOCreationError::WriteError)?;
+        compressed_size += trailer.len() as u64;
+    }
+
+    Ok(WriteReport {
+        header: *header,
+        compressed_size,
+        digests: hasher.map(crate::hash::Hasher::finish),
+    })
 }
 
 #[maybe_async]
 pub async fn write_ciso_image<I: SectorReader, O: AsyncWriter>(
     input: &mut I,
     output: &mut O,
+    progress_callback: impl FnMut(ProgressInfo),
+) -> Result<WriteReport, CSOCreationError<I::ReadError, O::WriteError>> {
+    write_ciso_image_with_options(input, output, &WriteOptions::default(), progress_callback)
+        .await
+}
+
+#[maybe_async]
+pub async fn write_ciso_image_with_options<I: SectorReader, O: AsyncWriter>(
+    input: &mut I,
+    output: &mut O,
+    options: &WriteOptions,
+    mut progress_callback: impl FnMut(ProgressInfo),
+) -> Result<WriteReport, CSOCreationError<I::ReadError, O::WriteError>> {
+    let header = {
+        let mut header = layout::CSOHeader::new();
+        header.uncompressed_size = input
+            .size()
+            .await
+            .map_err(CSOCreationError::ReadError)?;
+        header
+    };
+    let mut index_table = index::IndexTable::new(&header);
+    progress_callback(ProgressInfo::SectorCount(index_table.len()));
+
+    write_ciso_image_from(
+        input,
+        output,
+        &header,
+        &mut index_table,
+        0,
+        options,
+        &mut progress_callback,
+    )
+    .await
+}
+
+/// Continue an interrupted compression of `input` into `output`
+///
+/// `existing` reads back what was already written to `output`. The partial
+/// index table left by the last checkpoint is used to locate the written
+/// blocks, and the last of them is checked against the input before
+/// compression continues after it. If `existing` holds no usable image,
+/// compression starts from the beginning.
+#[maybe_async]
+pub async fn resume_ciso_image<I, R, O>(
+    input: &mut I,
+    existing: &mut R,
+    output: &mut O,
+    options: &WriteOptions,
     mut progress_callback: impl FnMut(ProgressInfo),
-) -> Result<(), CSOCreationError<I::ReadError, O::WriteError>> {
+) -> Result<WriteReport, CSOCreationError<I::ReadError, O::WriteError>>
+where
+    I: SectorReader,
+    O: AsyncWriter,
+    R: crate::read::Read<ReadError = O::WriteError>,
+{
     let header = {
         let mut header = layout::CSOHeader::new();
         header.uncompressed_size = input
@@ -130,15 +513,142 @@ pub async fn write_ciso_image<I: SectorReader, O: AsyncWriter>(
     let mut index_table = index::IndexTable::new(&header);
     progress_callback(ProgressInfo::SectorCount(index_table.len()));
 
+    let start_sector = resume_point(input, existing, &header, &mut index_table).await?;
+    if start_sector > 0 {
+        progress_callback(ProgressInfo::Resumed(start_sector));
+    }
+
+    write_ciso_image_from(
+        input,
+        output,
+        &header,
+        &mut index_table,
+        start_sector,
+        options,
+        &mut progress_callback,
+    )
+    .await
+}
+
+/// Find the sector to resume compression at, filling in the index entries
+/// of every block before it
+#[maybe_async]
+async fn resume_point<I, R, WE>(
+    input: &mut I,
+    existing: &mut R,
+    header: &layout::CSOHeader,
+    index_table: &mut index::IndexTable,
+) -> Result<usize, CSOCreationError<I::ReadError, WE>>
+where
+    I: SectorReader,
+    R: crate::read::Read<ReadError = WE>,
+{
+    let existing_size = existing
+        .size()
+        .await
+        .map_err(CSOCreationError::WriteError)?;
+    let index_end = 24 + 4 * index_table.len() as u64;
+    if existing_size < index_end {
+        return Ok(0);
+    }
+
+    let mut existing_header = [0; 24];
+    existing
+        .read(0, &mut existing_header)
+        .await
+        .map_err(CSOCreationError::WriteError)?;
+    let existing_header = match layout::CSOHeader::deserialize::<WE>(&existing_header) {
+        Ok(existing_header) => existing_header,
+        Err(_) => return Ok(0),
+    };
+
+    let uncompressed_size = existing_header.uncompressed_size;
+    if uncompressed_size != header.uncompressed_size
+        || existing_header.block_size != header.block_size
+        || existing_header.alignment != header.alignment
+    {
+        return Err(CSOCreationError::ResumeMismatch);
+    }
+
+    let mut existing_index = vec![0; 4 * index_table.len()];
+    existing
+        .read(24, &mut existing_index)
+        .await
+        .map_err(CSOCreationError::WriteError)?;
+    let existing_index = index::IndexTable::deserialize(existing_index);
+
+    // Checkpoints fill the index table in order, so the entries written so
+    // far are the leading non-zero ones.
+    let written = (0..existing_index.len())
+        .take_while(|idx| existing_index[*idx].raw_value() != 0)
+        .count();
+    if written == 0 {
+        return Ok(0);
+    }
+
+    let mut start_sector = written - 1;
+    while start_sector > 0 {
+        let sector = start_sector - 1;
+        let block_pos: u32 = existing_index[sector].position().into();
+        let block_end: u32 = existing_index[start_sector].position().into();
+        let block_pos = (block_pos as u64) << header.alignment;
+        let block_end = (block_end as u64) << header.alignment;
+
+        if block_end > block_pos && block_end <= existing_size {
+            let mut stored = vec![0; (block_end - block_pos) as usize];
+            existing
+                .read(block_pos, &mut stored)
+                .await
+                .map_err(CSOCreationError::WriteError)?;
+
+            let block_size = header.block_size as usize;
+            let lz4 = existing_index[sector].compression_type();
+            let stored = if !lz4 && stored.len() > block_size {
+                &stored[..block_size]
+            } else {
+                &stored
+            };
+
+            let expected = input
+                .read_sector(sector, header.block_size)
+                .await
+                .map_err(CSOCreationError::ReadError)?;
+            if crate::read::decode_block(block_size, lz4, stored).is_some_and(|b| b == expected) {
+                break;
+            }
+        }
+
+        start_sector -= 1;
+    }
+
+    for idx in 0..=start_sector {
+        index_table[idx] = existing_index[idx];
+    }
+
+    Ok(start_sector)
+}
+
+#[maybe_async]
+async fn write_ciso_image_from<I: SectorReader, O: AsyncWriter>(
+    input: &mut I,
+    output: &mut O,
+    header: &layout::CSOHeader,
+    index_table: &mut index::IndexTable,
+    start_sector: usize,
+    options: &WriteOptions,
+    mut progress_callback: impl FnMut(ProgressInfo),
+) -> Result<WriteReport, CSOCreationError<I::ReadError, O::WriteError>> {
     output
         .atomic_write(0, &header.serialize())
         .await
         .map_err(CSOCreationError::WriteError)?;
-    write_ciso_data(
+    let report = write_ciso_data(
         input,
         output,
-        &header,
-        &mut index_table,
+        header,
+        index_table,
+        start_sector,
+        options,
         &mut progress_callback,
     )
     .await?;
@@ -152,13 +662,18 @@ pub async fn write_ciso_image<I: SectorReader, O: AsyncWriter>(
 
     progress_callback(ProgressInfo::Finished);
 
-    Ok(())
+    Ok(report)
 }
 
 #[maybe_async]
 pub trait AsyncWriter: Send + Sync {
     type WriteError;
     async fn atomic_write(&mut self, position: u64, data: &[u8]) -> Result<(), Self::WriteError>;
+
+    /// Called before a block of `len` bytes, including its alignment
+    /// padding, is written at `position`, so that a writer splitting its
+    /// output can keep the whole block within one part
+    fn keep_together(&mut self, _position: u64, _len: u64) {}
 }
 
 #[maybe_async]
@@ -211,3 +726,158 @@ where
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::decode_block;
+
+    /// A block of repetitive text, which every codec compresses well
+    fn text_block() -> Vec<u8> {
+        let mut block = Vec::with_capacity(2048);
+        for i in 0.. {
+            if block.len() >= 2048 {
+                break;
+            }
+            block.extend_from_slice(format!("sector {} of the image; ", i % 17).as_bytes());
+        }
+        block.truncate(2048);
+        block
+    }
+
+    /// A block of `noise` pseudo-random bytes followed by zeros
+    fn noisy_block(noise: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9e37_79b9;
+        let mut block = vec![0; 2048];
+        for b in block[..noise].iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *b = state as u8;
+        }
+        block
+    }
+
+    fn options(codecs: &[BlockCodec]) -> WriteOptions {
+        WriteOptions {
+            codecs: codecs.to_vec(),
+            ..WriteOptions::default()
+        }
+    }
+
+    #[test]
+    fn encode_deflate_only() {
+        let block = text_block();
+        let options = options(&[BlockCodec::Deflate(9)]);
+        let (codec, data) = encode_block::<(), ()>(&options, &block, 4)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(codec, BlockCodec::Deflate(9));
+        assert!(data.len() < block.len());
+        assert_eq!(decode_block(2048, false, &data), Some(block));
+    }
+
+    #[test]
+    fn encode_picks_smallest_codec() {
+        let block = text_block();
+        let lz4 = compress_block::<(), ()>(BlockCodec::Lz4, &block).unwrap();
+        let deflate = compress_block::<(), ()>(BlockCodec::Deflate(9), &block).unwrap();
+
+        let options = options(&[BlockCodec::Lz4, BlockCodec::Deflate(9)]);
+        let (codec, data) = encode_block::<(), ()>(&options, &block, 4)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(data.len(), core::cmp::min(lz4.len(), deflate.len()));
+        let lz4_chosen = codec == BlockCodec::Lz4;
+        assert_eq!(lz4_chosen, lz4.len() < deflate.len());
+        assert_eq!(decode_block(2048, lz4_chosen, &data), Some(block));
+    }
+
+    #[test]
+    fn encode_deflate_near_block_size() {
+        let options = options(&[BlockCodec::Deflate(9)]);
+
+        // Find a block that deflates to just short enough with 4 byte
+        // alignment, but would reach a full block once padded to 256 bytes
+        let (block, len) = (1700..2048)
+            .map(noisy_block)
+            .find_map(|block| {
+                let data = compress_block::<(), ()>(BlockCodec::Deflate(9), &block).unwrap();
+                let len = data.len();
+                (len + 256 >= 2048 && len + 12 < 2048).then_some((block, len))
+            })
+            .unwrap();
+
+        let (_, data) = encode_block::<(), ()>(&options, &block, 4)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.len(), len);
+        let padded = (data.len() + 3) & !3;
+        assert!(padded < 2048);
+        assert_eq!(decode_block(2048, false, &data), Some(block.clone()));
+
+        let encoded = encode_block::<(), ()>(&options, &block, 256).unwrap();
+        assert!(encoded.is_none());
+    }
+
+    #[maybe_async::test(
+        feature = "sync",
+        async(all(not(feature = "sync"), feature = "tokio"), tokio::test)
+    )]
+    async fn fast_paths() {
+        let image = crate::split::tests::test_image(64);
+        let sector_of = |kind: usize| (0..64).filter(move |sector| sector % 7 == kind);
+        let zero_sectors: Vec<usize> = sector_of(3).collect();
+        let noise_sectors: Vec<usize> = sector_of(5).collect();
+
+        let mut output = crate::mem::MemFile::new(Vec::new());
+        let mut raw = Vec::new();
+        let mut fast_path = None;
+        write_ciso_image(
+            &mut crate::mem::MemFile::new(image.clone()),
+            &mut output,
+            |info| match info {
+                ProgressInfo::SectorFinished(block) if block.stored_raw => raw.push(block.sector),
+                ProgressInfo::FastPathBlocks { .. } => fast_path = Some(info),
+                _ => {}
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            fast_path,
+            Some(ProgressInfo::FastPathBlocks {
+                zero: 9,
+                incompressible: 9
+            })
+        ));
+        assert_eq!(raw, noise_sectors);
+
+        let cso = output.contents();
+        let index_table = index::IndexTable::deserialize(cso[24..][..65 * 4].to_vec());
+        let stored = |sector: usize| {
+            let pos: u32 = index_table[sector].position().into();
+            let next: u32 = index_table[sector + 1].position().into();
+            &cso[(pos as usize) << 2..(next as usize) << 2]
+        };
+
+        // Every zero block is stored as the one precomputed encoding
+        let (_, zero_block) = encode_block::<(), ()>(&WriteOptions::default(), &[0; 2048], 4)
+            .unwrap()
+            .unwrap();
+        let padded_len = (zero_block.len() + 3) & !3;
+        for sector in zero_sectors {
+            assert!(index_table[sector].compression_type());
+            assert_eq!(stored(sector)[..zero_block.len()], zero_block);
+            assert_eq!(stored(sector).len(), padded_len);
+        }
+
+        for sector in noise_sectors {
+            assert!(!index_table[sector].compression_type());
+            assert_eq!(stored(sector), &image[sector * 2048..][..2048]);
+        }
+    }
+}